@@ -0,0 +1,329 @@
+//! Special formations and unit abilities, eg shield wall, testudo and brace.
+//!
+//! Abilities are activated through `UnitUiCommand`s like any other order, so anything that can
+//! issue unit commands (the player, or an agent) can use them. `available_ability_commands` lists
+//! the ability commands a unit can currently use, which agents are offered through
+//! `agent::unit_actions` and scripts through their unit views.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::*;
+
+/// abilities that have been toggled off can't be used again for this many seconds
+const TOGGLE_COOLDOWN: f32 = 2.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Ability {
+    ShieldWall,
+    Testudo,
+    Phalanx,
+    Wedge,
+    CantabrianCircle,
+    Brace,
+}
+
+/// how long an ability stays active for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AbilityDuration {
+    /// stays on until toggled off
+    Toggle,
+    /// turns itself off after this many seconds
+    Timed(f32),
+}
+
+/// changes to a units stats while an ability is active
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AbilityModifiers {
+    pub melee_attack: f32,
+    pub melee_defence: f32,
+    /// extra percentage chance to block missiles from the front
+    pub missile_block: f32,
+    /// multiplier on the units movement speed
+    pub speed_factor: f32,
+}
+
+pub struct AbilityStats {
+    pub duration: AbilityDuration,
+    /// seconds before the ability can be used again after it ends
+    pub cooldown: f32,
+    pub modifiers: AbilityModifiers,
+}
+
+pub struct AbilitiesComponent {
+    /// abilities this unit type can use
+    pub available: Vec<Ability>,
+    /// only one ability can be active at a time
+    pub active: Option<Ability>,
+    /// seconds left on a timed ability
+    pub remaining: f32,
+    /// seconds until each ability can be used again
+    pub cooldowns: HashMap<Ability, f32>,
+}
+
+impl Default for AbilityModifiers {
+    fn default() -> Self {
+        AbilityModifiers {
+            melee_attack: 0.0,
+            melee_defence: 0.0,
+            missile_block: 0.0,
+            speed_factor: 1.0,
+        }
+    }
+}
+
+impl Ability {
+    pub const ALL: [Ability; 6] = [
+        Ability::ShieldWall,
+        Ability::Testudo,
+        Ability::Phalanx,
+        Ability::Wedge,
+        Ability::CantabrianCircle,
+        Ability::Brace,
+    ];
+
+    /// the name scripts use for the ability
+    pub fn name(&self) -> &'static str {
+        match self {
+            Ability::ShieldWall => "shield_wall",
+            Ability::Testudo => "testudo",
+            Ability::Phalanx => "phalanx",
+            Ability::Wedge => "wedge",
+            Ability::CantabrianCircle => "cantabrian_circle",
+            Ability::Brace => "brace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Ability> {
+        Ability::ALL.iter().find(|a| a.name() == name).cloned()
+    }
+
+    pub fn stats(&self) -> AbilityStats {
+        let (duration, cooldown, modifiers) = match self {
+            Ability::ShieldWall => (
+                AbilityDuration::Toggle,
+                TOGGLE_COOLDOWN,
+                AbilityModifiers {
+                    melee_defence: 10.0,
+                    missile_block: 25.0,
+                    speed_factor: 0.5,
+                    ..Default::default()
+                },
+            ),
+            Ability::Testudo => (
+                AbilityDuration::Toggle,
+                TOGGLE_COOLDOWN,
+                AbilityModifiers {
+                    melee_attack: -10.0,
+                    missile_block: 60.0,
+                    speed_factor: 0.3,
+                    ..Default::default()
+                },
+            ),
+            Ability::Phalanx => (
+                AbilityDuration::Toggle,
+                TOGGLE_COOLDOWN,
+                AbilityModifiers {
+                    melee_attack: 5.0,
+                    melee_defence: 20.0,
+                    speed_factor: 0.5,
+                    ..Default::default()
+                },
+            ),
+            Ability::Wedge => (
+                AbilityDuration::Toggle,
+                TOGGLE_COOLDOWN,
+                AbilityModifiers {
+                    melee_attack: 10.0,
+                    melee_defence: -5.0,
+                    ..Default::default()
+                },
+            ),
+            Ability::CantabrianCircle => (
+                AbilityDuration::Toggle,
+                TOGGLE_COOLDOWN,
+                AbilityModifiers {
+                    missile_block: 30.0,
+                    speed_factor: 0.6,
+                    ..Default::default()
+                },
+            ),
+            Ability::Brace => (
+                AbilityDuration::Timed(10.0),
+                30.0,
+                AbilityModifiers {
+                    melee_defence: 15.0,
+                    speed_factor: 0.0,
+                    ..Default::default()
+                },
+            ),
+        };
+
+        AbilityStats {
+            duration,
+            cooldown,
+            modifiers,
+        }
+    }
+}
+
+impl AbilitiesComponent {
+    pub fn default_from_type(unit_type: UnitType) -> Self {
+        use Ability::*;
+        let available = match unit_type {
+            UnitType::MeleeInfantry => vec![ShieldWall, Testudo],
+            UnitType::SpearInfantry => vec![ShieldWall, Brace],
+            UnitType::PikeInfantry => vec![Phalanx, Brace],
+            UnitType::MeleeCalvary | UnitType::ShockCalvary => vec![Wedge],
+            UnitType::MissileCalvary => vec![CantabrianCircle],
+            UnitType::ShockInfantry | UnitType::MissileInfantry => vec![],
+        };
+
+        AbilitiesComponent {
+            available,
+            active: None,
+            remaining: 0.0,
+            cooldowns: HashMap::new(),
+        }
+    }
+
+    pub fn is_ready(&self, ability: Ability) -> bool {
+        self.available.contains(&ability)
+            && self.cooldowns.get(&ability).cloned().unwrap_or(0.0) <= 0.0
+    }
+
+    /// returns false if the ability can't be used right now
+    pub fn activate(&mut self, ability: Ability) -> bool {
+        if self.active == Some(ability) {
+            return true;
+        }
+        if !self.is_ready(ability) {
+            log::debug!("ability {:?} not ready", ability);
+            return false;
+        }
+
+        self.deactivate();
+        self.active = Some(ability);
+        self.remaining = match ability.stats().duration {
+            AbilityDuration::Toggle => 0.0,
+            AbilityDuration::Timed(duration) => duration,
+        };
+        true
+    }
+
+    /// turns off the current ability, if any, and starts its cooldown
+    pub fn deactivate(&mut self) {
+        if let Some(ability) = self.active.take() {
+            self.cooldowns.insert(ability, ability.stats().cooldown);
+        }
+    }
+
+    pub fn toggle(&mut self, ability: Ability) {
+        if self.active == Some(ability) {
+            self.deactivate();
+        } else {
+            self.activate(ability);
+        }
+    }
+
+    /// the stat changes from the currently active ability
+    pub fn modifiers(&self) -> AbilityModifiers {
+        self.active.map(|a| a.stats().modifiers).unwrap_or_default()
+    }
+
+    /// advances cooldowns and timed abilities by `dt` seconds
+    pub fn tick(&mut self, dt: f32) {
+        for cooldown in self.cooldowns.values_mut() {
+            *cooldown = (*cooldown - dt).max(0.0);
+        }
+
+        if let Some(ability) = self.active {
+            if let AbilityDuration::Timed(_) = ability.stats().duration {
+                self.remaining -= dt;
+                if self.remaining <= 0.0 {
+                    self.deactivate();
+                }
+            }
+        }
+    }
+}
+
+impl CombatComponent {
+    /// combat stats with an abilities modifiers applied
+    pub fn with_modifiers(&self, modifiers: &AbilityModifiers) -> CombatComponent {
+        CombatComponent {
+            melee_attack: (self.melee_attack + modifiers.melee_attack).max(0.0),
+            melee_defence: (self.melee_defence + modifiers.melee_defence).max(0.0),
+            shield: (self.shield + modifiers.missile_block).min(100.0),
+            ..self.clone()
+        }
+    }
+}
+
+/// helper function
+/// combat stats for `entity` including any active ability
+pub fn effective_combat(
+    abilities: &Query<&AbilitiesComponent>,
+    entity: Entity,
+    combat: &CombatComponent,
+) -> CombatComponent {
+    abilities
+        .get_component::<AbilitiesComponent>(entity)
+        .map(|a| combat.with_modifiers(&a.modifiers()))
+        .unwrap_or_else(|_| combat.clone())
+}
+
+/// the ability commands a unit can issue right now
+pub fn available_ability_commands(abilities: &AbilitiesComponent) -> Vec<UnitUiCommand> {
+    let mut cmds: Vec<UnitUiCommand> = abilities
+        .available
+        .iter()
+        .filter(|a| abilities.active != Some(**a) && abilities.is_ready(**a))
+        .map(|a| UnitUiCommand::ActivateAbility(*a))
+        .collect();
+
+    if abilities.active.is_some() {
+        cmds.push(UnitUiCommand::DeactivateAbility);
+    }
+    cmds
+}
+
+/// ticks ability cooldowns and durations, and applies speed modifiers to units
+pub fn ability_system(
+    game_speed: Res<GameSpeed>,
+    time: Res<Time>,
+    mut query: Query<(&mut UnitComponent, &mut AbilitiesComponent)>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    for (mut unit, mut abilities) in query.iter_mut() {
+        abilities.tick(time.delta_seconds());
+        unit.speed_modifier = abilities.modifiers().speed_factor;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ability_cooldowns() {
+        let mut abilities = AbilitiesComponent::default_from_type(UnitType::SpearInfantry);
+        assert!(!abilities.activate(Ability::Testudo));
+
+        assert!(abilities.activate(Ability::Brace));
+        assert_eq!(abilities.modifiers().speed_factor, 0.0);
+
+        // brace wears off, and then goes on cooldown
+        abilities.tick(11.0);
+        assert_eq!(abilities.active, None);
+        assert!(!abilities.activate(Ability::Brace));
+
+        abilities.tick(30.0);
+        assert!(abilities.activate(Ability::Brace));
+    }
+}
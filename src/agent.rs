@@ -0,0 +1,186 @@
+//! What a learning agent sees of the battle, and what it can do.
+//!
+//! `agent_observation_system` refreshes the `AgentObservation` resource every tick. Each unit
+//! lists the commands it can be given right now that don't need a target or a position,
+//! including the ability commands from `available_ability_commands`. Attack and move commands
+//! are parameterised by the agent, and everything is sent as a `UnitInteractionEvent::Ui`.
+//! Capture points are observed too, with who owns them and how far along any capture is.
+
+use bevy::prelude::*;
+
+use crate::abilities::*;
+use crate::objectives::CapturePointComponent;
+use crate::teams::TeamId;
+use crate::victory::BattleResource;
+use crate::*;
+
+/// what an agent sees of a unit
+#[derive(Clone, Debug)]
+pub struct UnitObservation {
+    pub id: Entity,
+    pub player: PlayerId,
+    pub unit_type: UnitType,
+    pub position: XyPos,
+    pub facing: XyPos,
+    /// from 0 to 1
+    pub health: f32,
+    pub routing: bool,
+    /// commands the unit can be given right now that don't need a target or position
+    pub actions: Vec<UnitUiCommand>,
+}
+
+/// what an agent sees of a capture point
+#[derive(Clone, Debug)]
+pub struct ObjectiveObservation {
+    pub id: Entity,
+    pub position: XyPos,
+    pub radius: f32,
+    pub owner: Option<TeamId>,
+    pub contested: bool,
+    /// the team capturing the point, and how far along they are from 0 to 1
+    pub capture: Option<(TeamId, f32)>,
+}
+
+#[derive(Debug, Default)]
+pub struct AgentObservation {
+    /// seconds of battle time
+    pub elapsed: f32,
+    pub units: Vec<UnitObservation>,
+    pub objectives: Vec<ObjectiveObservation>,
+    /// a team whose side holds every objective, and for how many seconds
+    pub objective_holder: Option<(TeamId, f32)>,
+}
+
+impl AgentObservation {
+    /// every untargeted action `player` can take this tick, for each of their units
+    pub fn actions_for(&self, player: PlayerId) -> Vec<(Entity, UnitUiCommand)> {
+        self.units
+            .iter()
+            .filter(|u| u.player == player)
+            .flat_map(|u| u.actions.iter().map(move |cmd| (u.id, *cmd)))
+            .collect()
+    }
+}
+
+/// the commands a unit can be given right now that don't need a target or position
+pub fn unit_actions(
+    missiles: Option<&MissileWeaponComponent>,
+    abilities: Option<&AbilitiesComponent>,
+) -> Vec<UnitUiCommand> {
+    use UnitUiCommand::*;
+
+    let mut actions = vec![Stop, ToggleSpacing, ToggleSpeed, ToggleGuardMode];
+    match missiles {
+        Some(MissileWeaponComponent::Primary(_)) => {
+            actions.push(ToggleFireMode);
+            actions.push(ToggleFireAtWill);
+        }
+        Some(MissileWeaponComponent::Secondary(_)) => actions.push(ToggleFireAtWill),
+        _ => (),
+    }
+    actions.extend(
+        abilities
+            .map(available_ability_commands)
+            .unwrap_or_default(),
+    );
+    actions
+}
+
+/// refreshes the observation each tick, paused or not, so agents can plan while paused
+pub fn agent_observation_system(
+    battle: Res<BattleResource>,
+    mut observation: ResMut<AgentObservation>,
+    units: Query<(
+        Entity,
+        &UnitComponent,
+        &Transform,
+        &FacingComponent,
+        &HealthComponent,
+        Option<&MoraleComponent>,
+        Option<&MissileWeaponComponent>,
+        Option<&AbilitiesComponent>,
+    )>,
+    points: Query<(Entity, &CapturePointComponent, &Transform)>,
+) {
+    observation.elapsed = battle.elapsed;
+    observation.objective_holder = battle.objective_holder();
+    observation.units = units
+        .iter()
+        .map(
+            |(entity, unit, transform, facing, health, morale, missiles, abilities)| {
+                UnitObservation {
+                    id: entity,
+                    player: unit.player_id,
+                    unit_type: unit.unit_type,
+                    position: (transform.translation.x, transform.translation.y).into(),
+                    facing: facing.0,
+                    health: health.ratio(),
+                    routing: morale.map(|m| m.is_routing()).unwrap_or(false),
+                    actions: unit_actions(missiles, abilities),
+                }
+            },
+        )
+        .collect();
+    observation.objectives = points
+        .iter()
+        .map(|(entity, point, transform)| ObjectiveObservation {
+            id: entity,
+            position: (transform.translation.x, transform.translation.y).into(),
+            radius: point.radius,
+            owner: point.owner,
+            contested: point.contested,
+            capture: point
+                .progress
+                .map(|(team, time)| (team, time / point.capture_time)),
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_agent_actions() {
+        let mut abilities = AbilitiesComponent::default_from_type(UnitType::SpearInfantry);
+        let actions = unit_actions(Some(&MissileWeaponComponent::None), Some(&abilities));
+        assert!(actions
+            .iter()
+            .any(|a| matches!(a, UnitUiCommand::ActivateAbility(Ability::Brace))));
+        assert!(!actions
+            .iter()
+            .any(|a| matches!(a, UnitUiCommand::ToggleFireMode)));
+
+        // an active ability can be turned off, but not activated again
+        assert!(abilities.activate(Ability::Brace));
+        let bows = MissileWeaponComponent::Primary(MissileStats::new(MissileType::Bow, 10));
+        let actions = unit_actions(Some(&bows), Some(&abilities));
+        assert!(actions
+            .iter()
+            .any(|a| matches!(a, UnitUiCommand::DeactivateAbility)));
+        assert!(!actions
+            .iter()
+            .any(|a| matches!(a, UnitUiCommand::ActivateAbility(Ability::Brace))));
+        assert!(actions
+            .iter()
+            .any(|a| matches!(a, UnitUiCommand::ToggleFireMode)));
+
+        let unit = |id: u32, player: PlayerId| UnitObservation {
+            id: Entity::new(id),
+            player,
+            unit_type: UnitType::SpearInfantry,
+            position: XyPos::new(0.0, 0.0),
+            facing: XyPos::new(1.0, 0.0),
+            health: 1.0,
+            routing: false,
+            actions: actions.clone(),
+        };
+        let observation = AgentObservation {
+            units: vec![unit(1, 1), unit(2, 2)],
+            ..Default::default()
+        };
+        let mine = observation.actions_for(1);
+        assert_eq!(mine.len(), actions.len());
+        assert!(mine.iter().all(|(e, _)| *e == Entity::new(1)));
+    }
+}
@@ -1,81 +1,554 @@
 //! Combat must happen AFTER states have been calcualted so that we aren't in combat with a
 //! unit that got cleaned up at the end of the last loop
 
-// TODO unit timers on how fast combat should happen
+use std::collections::HashMap;
 
 use bevy::prelude::*;
+use serde::Deserialize;
 
+use crate::abilities::*;
+use crate::formation::*;
+use crate::line_of_sight::*;
+use crate::projectiles::*;
+use crate::soldiers::*;
 use crate::*;
 
+/// cos of the half-angle of the frontal arc (60 degrees either side of facing)
+const FRONT_ARC_COS: f32 = 0.5;
+/// cos of the half-angle outside of which an attack counts as from the rear (135 degrees)
+const REAR_ARC_COS: f32 = -0.707;
+const FLANK_DEFENCE_FACTOR: f32 = 0.6;
+const REAR_DEFENCE_FACTOR: f32 = 0.3;
+const FLANK_MORALE_PENALTY: f32 = 0.5;
+const REAR_MORALE_PENALTY: f32 = 1.0;
+
+/// where the matchup table is loaded from
+pub const MATCHUPS_PATH: &str = "assets/data/matchups.ron";
+
+/// the broad category of a defending unit that matchup bonuses are defined against
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+pub enum MatchupClass {
+    Infantry,
+    MissileInfantry,
+    Cavalry,
+}
+
+impl From<UnitType> for MatchupClass {
+    fn from(unit_type: UnitType) -> Self {
+        if unit_type.is_cavalry() {
+            MatchupClass::Cavalry
+        } else if unit_type.is_missile() {
+            MatchupClass::MissileInfantry
+        } else {
+            MatchupClass::Infantry
+        }
+    }
+}
+
+/// extra stats an attacker gets when fighting a particular class of unit
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MatchupBonus {
+    pub melee_attack: f32,
+    pub damage: f32,
+}
+
+impl MatchupBonus {
+    pub fn is_none(&self) -> bool {
+        self.melee_attack == 0.0 && self.damage == 0.0
+    }
+}
+
+/// one row of the matchup table file
+#[derive(Debug, Deserialize)]
+struct MatchupEntry {
+    attacker: UnitType,
+    defender: MatchupClass,
+    melee_attack: f32,
+    damage: f32,
+}
+
+/// data-driven lookup of bonuses for an attacking unit type vs a defending class, loaded from
+/// `MATCHUPS_PATH`. Any pair not in the table gets no bonus. The `Default` table is only a
+/// fallback for when the file can't be loaded.
+#[derive(Debug, PartialEq)]
+pub struct MatchupTable(pub HashMap<(UnitType, MatchupClass), MatchupBonus>);
+
+impl MatchupTable {
+    /// parses a list of `(attacker, defender, melee_attack, damage)` entries in RON
+    pub fn from_ron(source: &str) -> Result<Self, ron::Error> {
+        let entries: Vec<MatchupEntry> = ron::de::from_str(source)?;
+        Ok(MatchupTable(
+            entries
+                .into_iter()
+                .map(|e| {
+                    let bonus = MatchupBonus {
+                        melee_attack: e.melee_attack,
+                        damage: e.damage,
+                    };
+                    ((e.attacker, e.defender), bonus)
+                })
+                .collect(),
+        ))
+    }
+
+    /// loads the table from `path`, falling back to the built in one if it can't be read
+    pub fn load(path: &str) -> Self {
+        std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|source| MatchupTable::from_ron(&source).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                log::error!("can't load matchups from {}, using defaults: {}", path, e);
+                MatchupTable::default()
+            })
+    }
+
+    pub fn bonus(&self, attacker: UnitType, defender: UnitType) -> MatchupBonus {
+        self.0
+            .get(&(attacker, defender.into()))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl Default for MatchupTable {
+    fn default() -> Self {
+        use MatchupClass::*;
+        use UnitType::*;
+
+        let bonus = |melee_attack, damage| MatchupBonus {
+            melee_attack,
+            damage,
+        };
+
+        let mut table = HashMap::new();
+        table.insert((SpearInfantry, Cavalry), bonus(15.0, 10.0));
+        table.insert((PikeInfantry, Cavalry), bonus(20.0, 12.0));
+        table.insert((ShockInfantry, Infantry), bonus(5.0, 5.0));
+        table.insert((MeleeCalvary, MissileInfantry), bonus(10.0, 8.0));
+        table.insert((ShockCalvary, MissileInfantry), bonus(12.0, 12.0));
+        table.insert((ShockCalvary, Infantry), bonus(0.0, 5.0));
+        MatchupTable(table)
+    }
+}
+
+/// a matchup bonus that was applied this tick, for the debug overlay
+#[derive(Clone, Copy, Debug)]
+pub struct ActiveMatchup {
+    pub attacker: Entity,
+    pub defender: Entity,
+    pub attacker_type: UnitType,
+    pub defender_type: UnitType,
+    pub bonus: MatchupBonus,
+}
+
+/// all matchup bonuses applied during the current tick, by melee and missiles.
+/// Cleared at the start of each tick by `clear_matchups_system`.
+#[derive(Default)]
+pub struct ActiveMatchups(pub Vec<ActiveMatchup>);
+
+/// where a unit (or soldier) is in its melee attack cycle
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttackPhase {
+    Ready,
+    /// seconds until the attack lands
+    WindUp(f32),
+    /// seconds until the next attack can start
+    Recovery(f32),
+}
+
+/// paces melee attacks, so damage is dealt at a fixed rate regardless of framerate
+#[derive(Debug)]
+pub struct MeleeTimerComponent {
+    pub phase: AttackPhase,
+}
+
+impl Default for MeleeTimerComponent {
+    fn default() -> Self {
+        MeleeTimerComponent {
+            phase: AttackPhase::Ready,
+        }
+    }
+}
+
+impl MeleeTimerComponent {
+    /// advances the attack cycle by `dt` seconds, returning the number of attacks that landed.
+    /// Attacks only wind up while `engaged`, and are abandoned if the unit disengages.
+    pub fn tick(&mut self, dt: f32, engaged: bool, stats: &CombatComponent) -> usize {
+        if stats.attack_interval() <= 0.0 {
+            return 0;
+        }
+
+        let mut strikes = 0;
+        let mut remaining = dt;
+        loop {
+            match self.phase {
+                AttackPhase::Ready if engaged => self.phase = AttackPhase::WindUp(stats.wind_up),
+                AttackPhase::Ready => break,
+                AttackPhase::WindUp(_) if !engaged => {
+                    self.phase = AttackPhase::Ready;
+                    break;
+                }
+                AttackPhase::WindUp(t) | AttackPhase::Recovery(t) if t > remaining => {
+                    self.phase = match self.phase {
+                        AttackPhase::WindUp(_) => AttackPhase::WindUp(t - remaining),
+                        _ => AttackPhase::Recovery(t - remaining),
+                    };
+                    break;
+                }
+                AttackPhase::WindUp(t) => {
+                    remaining -= t;
+                    strikes += 1;
+                    self.phase = AttackPhase::Recovery(stats.recovery);
+                }
+                AttackPhase::Recovery(t) => {
+                    remaining -= t;
+                    self.phase = AttackPhase::Ready;
+                }
+            }
+        }
+        strikes
+    }
+}
+
+/// runs before anything can deal damage, so the overlay only shows this ticks matchups
+pub fn clear_matchups_system(mut active_matchups: ResMut<ActiveMatchups>) {
+    active_matchups.0.clear();
+}
+
 pub fn unit_melee_system(
     mut unit_events: ResMut<Events<UnitInteractionEvent>>,
     game_speed: Res<GameSpeed>,
-    unit_query: Query<(&UnitComponent, &CombatComponent)>,
+    time: Res<Time>,
+    matchups: Res<MatchupTable>,
+    mut active_matchups: ResMut<ActiveMatchups>,
+    mut unit_query: Query<(Entity, &UnitComponent, &CombatComponent, &mut MeleeTimerComponent)>,
     mut health_query: Query<&mut HealthComponent>,
-    target_query: Query<&CombatComponent>
+    mut morale_query: Query<&mut MoraleComponent>,
+    target_query: Query<(&UnitComponent, &CombatComponent)>,
+    position_query: Query<(&Transform, &FacingComponent)>,
+    soldier_units: Query<&SoldiersComponent>,
+    mut soldier_query: Query<&mut SoldierComponent>,
+    formations: Query<&FormationComponent>,
+    abilities: Query<&AbilitiesComponent>,
 ) {
     if game_speed.is_paused() {
         return;
     }
-    
-    for (unit, source) in unit_query.iter() {
+
+    for (entity, unit, source, mut timer) in unit_query.iter_mut() {
+        let engaged = matches!(unit.state, UnitState::Melee(Some(_)));
+        let strikes = timer.tick(time.delta_seconds(), engaged, source);
+
         if let UnitState::Melee(Some(target)) = unit.state {
-            let mut target_heath = health_query.get_component_mut::<HealthComponent>(target).unwrap();
-            let target_combat = target_query.get_component::<CombatComponent>(target).unwrap();
-            if calc_melee_hit(source, &target_combat) {
-                target_heath.current_health -= calc_damage(source, &target_combat);
+            let source = effective_combat(&abilities, entity, source);
+            let target_combat = effective_combat(
+                &abilities,
+                target,
+                target_query.get_component::<CombatComponent>(target).unwrap(),
+            );
+            let target_unit = target_query.get_component::<UnitComponent>(target).unwrap();
+
+            let bonus = matchups.bonus(unit.unit_type, target_unit.unit_type);
+            if !bonus.is_none() {
+                active_matchups.0.push(ActiveMatchup {
+                    attacker: entity,
+                    defender: target,
+                    attacker_type: unit.unit_type,
+                    defender_type: target_unit.unit_type,
+                    bonus,
+                });
+            }
+
+            // units made of soldiers resolve their own hits in `soldier_melee_system`
+            if soldier_units.get_component::<SoldiersComponent>(entity).is_ok() {
+                continue;
+            }
+
+            let direction = attack_direction_between(&position_query, entity, target);
+
+            for _ in 0..strikes {
+                if !calc_melee_hit(&source, &target_combat, &bonus, direction) {
+                    continue;
+                }
+
+                // wider formations get more men into the fight
+                let frontage_factor = formations
+                    .get_component::<FormationComponent>(entity)
+                    .map(|f| f.frontage_factor(SINGLE_BODY_MEN))
+                    .unwrap_or(1.0);
+                let damage = calc_damage(&source, &target_combat, &bonus) * frontage_factor;
+                apply_direction_morale_penalty(&mut morale_query, target, direction);
+
+                if let Ok(target_soldiers) = soldier_units.get_component::<SoldiersComponent>(target) {
+                    // the front rank takes the hit, casualties are cleaned up in `soldier_casualty_system`
+                    if let Some(casualty) = target_soldiers.melee_casualty() {
+                        soldier_query.get_component_mut::<SoldierComponent>(casualty).unwrap().current_health -= damage;
+                    }
+                } else {
+                    let mut target_heath = health_query.get_component_mut::<HealthComponent>(target).unwrap();
+                    target_heath.current_health -= damage;
             
-                if target_heath.current_health < 0.0 {
-                    log::info!("unit dead!");
-                    unit_events.send(UnitInteractionEvent::UnitDied(target));
+                    if target_heath.current_health < 0.0 {
+                        log::info!("unit dead!");
+                        unit_events.send(UnitInteractionEvent::UnitDied(target));
+                    }
                 }
             }
         }
     }
 }
 
+/// launches projectiles at the target of each firing unit, whenever their weapon has
+/// reloaded and they have finished aiming. Damage is dealt when they land, see
+/// `projectile_impact_system`
 pub fn unit_missile_system(
-    mut unit_events: ResMut<Events<UnitInteractionEvent>>,
+    mut commands: Commands,
     game_speed: Res<GameSpeed>,
-    mut unit_query: Query<(&UnitComponent, &CombatComponent, &mut MissileWeaponComponent)>,
-    mut health_query: Query<&mut HealthComponent>,
-    target_query: Query<&CombatComponent>,
+    time: Res<Time>,
+    materials: Res<ProjectileMaterials>,
+    mut unit_query: Query<(
+        Entity,
+        &mut UnitComponent,
+        &VelocityComponent,
+        &mut MissileWeaponComponent,
+        Option<&LineOfSightComponent>,
+    )>,
+    transform_query: Query<&Transform>,
+    sprite_query: Query<&Sprite>,
 ) {
     if game_speed.is_paused() {
         return;
     }
     
-    for (unit, source, mut missile) in unit_query.iter_mut() {
+    for (entity, mut unit, velocity, mut missile, los) in unit_query.iter_mut() {
+        let is_secondary = matches!(*missile, MissileWeaponComponent::Secondary(_));
+        let stats = match missile.stats_mut() {
+            Some(stats) => stats,
+            None => continue,
+        };
+
+        // horse archers can keep their aim on the move
+        let moving = velocity.0 > 0.0 && !unit.can_fire_while_moving();
+        stats.tick(time.delta_seconds(), moving);
+
         if let UnitState::Firing(Some(target)) | UnitState::FiringAndMoving(Some(target)) = unit.state {
-            debug_assert!(missile.is_missile_attack_available());
+            if !stats.is_ready_to_fire() {
+                continue;
+            }
 
-            missile.use_ammo();
+            // the target may have been despawned this frame
+            let (source_pos, target_pos) = match (
+                transform_query.get_component::<Transform>(entity),
+                transform_query.get_component::<Transform>(target),
+            ) {
+                (Ok(source), Ok(target)) => (source.translation, target.translation),
+                _ => continue,
+            };
 
-            let mut target_heath = health_query.get_component_mut::<HealthComponent>(target).unwrap();
-            let target_combat = target_query.get_component::<CombatComponent>(target).unwrap();
-            target_heath.current_health -= calc_damage(source, &target_combat);
+            let missile_type = stats.type_;
+            let shots = stats.fire();
+            if is_secondary {
+                // one volley, then in to melee
+                unit.throw_secondary = false;
+            }
+            let target_size = sprite_query.get_component::<Sprite>(target).map(|s| s.size).unwrap_or_default();
+
+            let scatter_factor = los.map(|los| los.scatter_factor(target)).unwrap_or(1.0);
 
-            if target_heath.current_health < 0.0 {
-                log::info!("unit dead!");
-                unit_events.send(UnitInteractionEvent::UnitDied(target));
+            for _ in 0..shots {
+                let projectile = ProjectileComponent::launch(
+                    entity,
+                    unit.unit_type,
+                    missile_type,
+                    (source_pos.x, source_pos.y).into(),
+                    (target_pos.x, target_pos.y).into(),
+                    target_size,
+                    scatter_factor,
+                );
+                spawn_projectile(&mut commands, &materials, projectile);
             }
         }
     }
 }
 
+/// which side of the target an attack lands on, relative to the target's facing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttackDirection {
+    Front,
+    Flank,
+    Rear,
+}
+
+/// classifies an attack by the angle between the target's facing and the direction
+/// from the target to the attacker
+pub fn attack_direction(
+    target_pos: XyPos,
+    target_facing: XyPos,
+    attacker_pos: XyPos,
+) -> AttackDirection {
+    let to_attacker = attacker_pos - target_pos;
+    if to_attacker.length_squared() == 0.0 {
+        return AttackDirection::Front;
+    }
+
+    let cos_angle = target_facing.dot(to_attacker.normalize());
+    if cos_angle >= FRONT_ARC_COS {
+        AttackDirection::Front
+    } else if cos_angle <= REAR_ARC_COS {
+        AttackDirection::Rear
+    } else {
+        AttackDirection::Flank
+    }
+}
+
+/// helper function
+fn attack_direction_between(
+    position_query: &Query<(&Transform, &FacingComponent)>,
+    attacker: Entity,
+    target: Entity,
+) -> AttackDirection {
+    let attacker_pos = position_query
+        .get_component::<Transform>(attacker)
+        .unwrap()
+        .translation;
+    let target_pos = position_query
+        .get_component::<Transform>(target)
+        .unwrap()
+        .translation;
+    let target_facing = position_query
+        .get_component::<FacingComponent>(target)
+        .unwrap();
+
+    attack_direction(
+        (target_pos.x, target_pos.y).into(),
+        target_facing.0,
+        (attacker_pos.x, attacker_pos.y).into(),
+    )
+}
+
+/// helper function
+/// not every unit has morale, so those without a morale component are skipped
+pub(crate) fn apply_direction_morale_penalty(
+    morale_query: &mut Query<&mut MoraleComponent>,
+    target: Entity,
+    direction: AttackDirection,
+) {
+    let penalty = match direction {
+        AttackDirection::Front => return,
+        AttackDirection::Flank => FLANK_MORALE_PENALTY,
+        AttackDirection::Rear => REAR_MORALE_PENALTY,
+    };
+    if let Ok(mut morale) = morale_query.get_component_mut::<MoraleComponent>(target) {
+        morale.apply_penalty(penalty);
+    }
+}
+
 /// TODD make determinisic
 /// AP damage is always applied. Armour is rolled between 0-100% of base armour value, 
-/// then subtracted from source normal attack damage
-fn calc_damage(source: &CombatComponent, target: &CombatComponent) -> f32 {
-    source.normal_damage 
+/// then subtracted from source normal attack damage plus any matchup bonus damage
+pub(crate) fn calc_damage(source: &CombatComponent, target: &CombatComponent, bonus: &MatchupBonus) -> f32 {
+    source.normal_damage + bonus.damage
         - (target.armour * rand::random::<f32>()) 
         + source.ap_damage
 }
 
+/// TODD make determinisic
+/// same as `calc_damage`, but using the missiles damage, and some missiles ignore part of
+/// the targets armour
+pub(crate) fn calc_missile_damage(
+    missile: &MissileProfile,
+    target: &CombatComponent,
+    bonus: &MatchupBonus,
+) -> f32 {
+    missile.normal_damage + bonus.damage
+        - (target.armour * (1.0 - missile.armour_ignored) * rand::random::<f32>())
+        + missile.ap_damage
+}
+
+/// TODD make determinisic
+/// melee attack (plus matchup bonus) and melee defence are independantly rolled, and if
+/// roll_attack is higher a hit is scored. Attacks from the flank or rear reduce the
+/// targets effective melee defence
+pub(crate) fn calc_melee_hit(
+    source: &CombatComponent,
+    target: &CombatComponent,
+    bonus: &MatchupBonus,
+    direction: AttackDirection,
+) -> bool {
+    let melee_defence = match direction {
+        AttackDirection::Front => target.melee_defence,
+        AttackDirection::Flank => target.melee_defence * FLANK_DEFENCE_FACTOR,
+        AttackDirection::Rear => target.melee_defence * REAR_DEFENCE_FACTOR,
+    };
+    (source.melee_attack + bonus.melee_attack) * rand::random::<f32>() > melee_defence * rand::random::<f32>() 
+}
 
 /// TODD make determinisic
-/// melee attack and melee defence are independantly rolled, and if roll_attack is higher
-/// a hit is scored
-fn calc_melee_hit(source: &CombatComponent, target: &CombatComponent) -> bool {
-    source.melee_attack * rand::random::<f32>() > target.melee_defence  * rand::random::<f32>() 
-}
\ No newline at end of file
+/// shields only block missiles coming from the front
+pub(crate) fn calc_shield_block(target: &CombatComponent, direction: AttackDirection) -> bool {
+    direction == AttackDirection::Front && rand::random::<f32>() * 100.0 < target.shield
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matchup_table_file() {
+        let table = MatchupTable::from_ron(include_str!("../assets/data/matchups.ron"));
+        assert_eq!(table.expect("parses"), MatchupTable::default());
+        assert!(MatchupTable::from_ron("[(attacker: Dragon)]").is_err());
+    }
+
+    #[test]
+    fn test_matchup_table_lookup() {
+        let table = MatchupTable::default();
+        assert!(!table
+            .bonus(UnitType::SpearInfantry, UnitType::ShockCalvary)
+            .is_none());
+        assert!(!table
+            .bonus(UnitType::MeleeCalvary, UnitType::MissileInfantry)
+            .is_none());
+        assert!(table
+            .bonus(UnitType::SpearInfantry, UnitType::MeleeInfantry)
+            .is_none());
+    }
+
+    #[test]
+    fn test_attack_direction() {
+        let target_pos = XyPos::new(0.0, 0.0);
+        let facing = XyPos::new(1.0, 0.0);
+        assert_eq!(
+            attack_direction(target_pos, facing, XyPos::new(10.0, 1.0)),
+            AttackDirection::Front
+        );
+        assert_eq!(
+            attack_direction(target_pos, facing, XyPos::new(0.0, 10.0)),
+            AttackDirection::Flank
+        );
+        assert_eq!(
+            attack_direction(target_pos, facing, XyPos::new(-10.0, 1.0)),
+            AttackDirection::Rear
+        );
+    }
+
+    #[test]
+    fn test_melee_timer_is_framerate_independent() {
+        let stats = CombatComponent::default_from_type(UnitType::MeleeInfantry);
+
+        let mut fast = MeleeTimerComponent::default();
+        let mut slow = MeleeTimerComponent::default();
+        // 6 seconds either way, in steps that add up exactly
+        let fast_strikes: usize = (0..384).map(|_| fast.tick(1.0 / 64.0, true, &stats)).sum();
+        let slow_strikes: usize = (0..24).map(|_| slow.tick(0.25, true, &stats)).sum();
+
+        // the phase lengths themselves aren't exact, so a strike can land either side of the end
+        let expected = 6.0 / stats.attack_interval();
+        assert!((fast_strikes as f32 - slow_strikes as f32).abs() <= 1.0);
+        assert!((fast_strikes as f32 - expected).abs() <= 1.0);
+
+        // disengaging abandons the attack
+        fast.tick(0.1, false, &stats);
+        assert_eq!(fast.phase, AttackPhase::Ready);
+    }
+}
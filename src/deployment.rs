@@ -0,0 +1,411 @@
+//! The pre-battle deployment phase.
+//!
+//! If a scenario has a deployment setup, the battle starts paused and each player can move
+//! their units around inside their team's deployment zone. Humans drag and drop units with the
+//! mouse, and agents send `DeploymentCommand`s. Once every player that needs to has readied up,
+//! the battle starts.
+//!
+//! Accepted placements are recorded by the units index in the scenario. Once deployment is over
+//! the scenario is saved with them as the `placements` of its `DeploymentSetup` (see
+//! `Scenario::replay_path`), so the battle can be replayed from the same start.
+
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use bevy_input::keyboard::*;
+use bevy_input::mouse::*;
+use bevy_rapier2d::physics::{ColliderHandleComponent, RigidBodyHandleComponent};
+use bevy_rapier2d::rapier::dynamics::RigidBodySet;
+use bevy_rapier2d::rapier::geometry::ColliderSet;
+use bevy_rapier2d::rapier::math::{Isometry, Vector};
+use serde::{Deserialize, Serialize};
+
+use crate::formation::*;
+use crate::scenario::Scenario;
+use crate::soldiers::SoldiersComponent;
+use crate::teams::*;
+use crate::user_input::CursorState;
+use crate::*;
+
+/// an axis-aligned box that a team can deploy in
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeploymentZone {
+    pub min: XyPos,
+    pub max: XyPos,
+}
+
+/// how deployment works for a scenario
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DeploymentSetup {
+    /// where each team can deploy. Teams without a zone can't move their units.
+    pub zones: Vec<(TeamId, DeploymentZone)>,
+    /// players that have to ready up before the battle starts. Everyone else is assumed ready,
+    /// eg scripted AI players.
+    pub ready_players: Vec<PlayerId>,
+    /// placements made as soon as deployment starts, eg ones recorded in an earlier battle
+    #[serde(default)]
+    pub placements: Vec<DeploymentPlacement>,
+}
+
+/// a unit placed during deployment, by its index in the scenarios unit list
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeploymentPlacement {
+    pub unit: usize,
+    pub position: XyPos,
+}
+
+/// sent by humans (via the mouse and keyboard) or agents during deployment
+#[derive(Clone, Copy, Debug)]
+pub enum DeploymentCommand {
+    /// move one of the players units
+    Place {
+        player: PlayerId,
+        unit: Entity,
+        position: XyPos,
+    },
+    /// the player has finished deploying
+    Ready(PlayerId),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeploymentError {
+    NotDeploying,
+    NotOwner,
+    NoZone,
+    OutsideZone,
+}
+
+#[derive(Debug, Default)]
+pub struct DeploymentResource {
+    pub setup: DeploymentSetup,
+    /// true until everyone has readied up
+    pub is_deploying: bool,
+    pub ready: HashSet<PlayerId>,
+    /// the entity spawned for each of the scenarios units, in order
+    pub scenario_units: Vec<Entity>,
+    /// every placement accepted so far, in order
+    pub recorded: Vec<DeploymentPlacement>,
+    /// set once the setups placements have been made
+    replayed: bool,
+}
+
+#[derive(Default)]
+pub struct DeploymentInputState {
+    mousebtn: EventReader<MouseButtonInput>,
+    keys: EventReader<KeyboardInput>,
+    /// the unit being dragged
+    dragging: Option<Entity>,
+}
+
+#[derive(Default)]
+pub struct DeploymentCommandState {
+    commands: EventReader<DeploymentCommand>,
+}
+
+impl DeploymentZone {
+    pub fn new(min: XyPos, max: XyPos) -> Self {
+        DeploymentZone { min, max }
+    }
+
+    pub fn contains(&self, pos: XyPos) -> bool {
+        pos.x >= self.min.x && pos.x <= self.max.x && pos.y >= self.min.y && pos.y <= self.max.y
+    }
+}
+
+impl DeploymentSetup {
+    pub fn zone(&self, team: TeamId) -> Option<DeploymentZone> {
+        self.zones
+            .iter()
+            .find(|(t, _)| *t == team)
+            .map(|(_, zone)| *zone)
+    }
+}
+
+impl DeploymentResource {
+    /// with no setup, the battle starts straight away
+    pub fn new(setup: Option<DeploymentSetup>) -> Self {
+        DeploymentResource {
+            is_deploying: setup.is_some(),
+            setup: setup.unwrap_or_default(),
+            ready: HashSet::new(),
+            scenario_units: vec![],
+            recorded: vec![],
+            replayed: false,
+        }
+    }
+
+    /// checks whether `player` can put `unit` (owned by `owner`) at `position`
+    pub fn check_placement(
+        &self,
+        teams: &TeamsResource,
+        player: PlayerId,
+        owner: PlayerId,
+        position: XyPos,
+    ) -> Result<(), DeploymentError> {
+        if !self.is_deploying {
+            return Err(DeploymentError::NotDeploying);
+        }
+        if player != owner {
+            return Err(DeploymentError::NotOwner);
+        }
+        let zone = teams
+            .team_of(player)
+            .ok()
+            .and_then(|team| self.setup.zone(team))
+            .ok_or(DeploymentError::NoZone)?;
+        if zone.contains(position) {
+            Ok(())
+        } else {
+            Err(DeploymentError::OutsideZone)
+        }
+    }
+
+    /// the setups placements as commands from each units owner. Only returns them once.
+    pub fn replay_commands(
+        &mut self,
+        owner: &dyn Fn(Entity) -> Option<PlayerId>,
+    ) -> Vec<DeploymentCommand> {
+        if self.replayed {
+            return vec![];
+        }
+        self.replayed = true;
+
+        let units = &self.scenario_units;
+        self.setup
+            .placements
+            .iter()
+            .filter_map(|p| {
+                let unit = *units.get(p.unit)?;
+                Some(DeploymentCommand::Place {
+                    player: owner(unit)?,
+                    unit,
+                    position: p.position,
+                })
+            })
+            .collect()
+    }
+
+    /// records an accepted placement. Units that weren't spawned by the scenario aren't recorded.
+    pub fn record(&mut self, unit: Entity, position: XyPos) {
+        if let Some(idx) = self.scenario_units.iter().position(|e| *e == unit) {
+            self.recorded.push(DeploymentPlacement {
+                unit: idx,
+                position,
+            });
+        }
+    }
+
+    /// the deployment setup with the placements made in this battle, to replay them later
+    pub fn recorded_setup(&self) -> DeploymentSetup {
+        DeploymentSetup {
+            placements: self.recorded.clone(),
+            ..self.setup.clone()
+        }
+    }
+
+    pub fn everyone_ready(&self) -> bool {
+        self.setup
+            .ready_players
+            .iter()
+            .all(|p| self.ready.contains(p))
+    }
+}
+
+/// drag and drop for the local player, and enter to ready up
+pub fn deployment_input_system(
+    mut state: Local<DeploymentInputState>,
+    deployment: Res<DeploymentResource>,
+    local_player: Res<LocalPlayer>,
+    cursor: Res<CursorState>,
+    ev_mousebtn: Res<Events<MouseButtonInput>>,
+    ev_keys: Res<Events<KeyboardInput>>,
+    mut commands: ResMut<Events<DeploymentCommand>>,
+    units: Query<(Entity, &UnitComponent, &Transform, &Sprite)>,
+) {
+    if !deployment.is_deploying {
+        return;
+    }
+    let state = &mut *state;
+
+    for ev in state.mousebtn.iter(&ev_mousebtn) {
+        if ev.button != MouseButton::Left {
+            continue;
+        }
+        let pos = cursor.last_pos;
+        if ev.state.is_pressed() {
+            state.dragging = units
+                .iter()
+                .find(|(_, unit, transform, sprite)| {
+                    let half = sprite.size / 2.0;
+                    unit.player_id == local_player.0
+                        && (pos.x - transform.translation.x).abs() < half.x
+                        && (pos.y - transform.translation.y).abs() < half.y
+                })
+                .map(|(entity, _, _, _)| entity);
+        } else if let Some(unit) = state.dragging.take() {
+            commands.send(DeploymentCommand::Place {
+                player: local_player.0,
+                unit,
+                position: pos,
+            });
+        }
+    }
+
+    for ev in state.keys.iter(&ev_keys) {
+        if ev.state.is_pressed() && ev.key_code == Some(KeyCode::Return) {
+            commands.send(DeploymentCommand::Ready(local_player.0));
+        }
+    }
+}
+
+/// moves deployed units, and starts the battle once everyone is ready
+pub fn deployment_system(
+    mut state: Local<DeploymentCommandState>,
+    mut deployment: ResMut<DeploymentResource>,
+    mut game_speed: ResMut<GameSpeed>,
+    teams: Res<TeamsResource>,
+    scenario: Res<Scenario>,
+    events: Res<Events<DeploymentCommand>>,
+    mut bodies: ResMut<RigidBodySet>,
+    mut colliders: ResMut<ColliderSet>,
+    units: Query<(
+        &UnitComponent,
+        &FacingComponent,
+        &FormationComponent,
+        &RigidBodyHandleComponent,
+        &ColliderHandleComponent,
+        Option<&SoldiersComponent>,
+    )>,
+    soldiers: Query<(&RigidBodyHandleComponent, &ColliderHandleComponent)>,
+) {
+    if !deployment.is_deploying {
+        return;
+    }
+    // the clock doesn't start until deployment is over
+    game_speed.pause();
+
+    let owner = |e: Entity| {
+        units
+            .get_component::<UnitComponent>(e)
+            .ok()
+            .map(|u| u.player_id)
+    };
+    let mut cmds = deployment.replay_commands(&owner);
+    cmds.extend(state.commands.iter(&events).cloned());
+
+    for cmd in cmds {
+        match cmd {
+            DeploymentCommand::Place {
+                player,
+                unit,
+                position,
+            } => {
+                let (unit_component, facing, formation, body, collider, unit_soldiers) =
+                    match units.get(unit) {
+                        Ok(u) => u,
+                        Err(_) => continue,
+                    };
+                if let Err(e) =
+                    deployment.check_placement(&teams, player, unit_component.player_id, position)
+                {
+                    log::warn!("player {} can't deploy {:?} there: {:?}", player, unit, e);
+                    continue;
+                }
+                deployment.record(unit, position);
+
+                let mut place = |body: &RigidBodyHandleComponent,
+                                 collider: &ColliderHandleComponent,
+                                 xy: XyPos| {
+                    let pos = Isometry::new(Vector::new(xy.x, xy.y), facing_angle(facing.0));
+                    if let Some(body) = bodies.get_mut(body.handle()) {
+                        body.set_position(pos, true);
+                    }
+                    if let Some(collider) = colliders.get_mut(collider.handle()) {
+                        collider.set_position_debug(pos);
+                    }
+                };
+
+                place(body, collider, position);
+                if let Some(unit_soldiers) = unit_soldiers {
+                    let count = unit_soldiers.soldiers.len();
+                    for (slot, soldier) in unit_soldiers.soldiers.iter().enumerate() {
+                        if let Ok((body, collider)) = soldiers.get(soldier.clone()) {
+                            let offset = formation.slot_offset(slot, count, facing.0);
+                            place(body, collider, position + offset);
+                        }
+                    }
+                }
+            }
+            DeploymentCommand::Ready(player) => {
+                log::info!("player {} is ready", player);
+                deployment.ready.insert(player);
+            }
+        }
+    }
+
+    if deployment.everyone_ready() {
+        log::info!("deployment over, starting the battle");
+        let replay = scenario.with_deployment(deployment.recorded_setup());
+        let path = replay.replay_path();
+        match replay.save(&path) {
+            Ok(()) => log::info!("saved the deployment to {}", path),
+            Err(e) => log::error!("can't save the deployment to {}: {}", path, e),
+        }
+        deployment.is_deploying = false;
+        game_speed.unpause();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_deployment_placement() {
+        let teams = TeamsResource::from_setup(&TeamSetup::free_for_all(&[1, 2]));
+        let zone = DeploymentZone::new(XyPos::new(0.0, 0.0), XyPos::new(100.0, 100.0));
+        let mut deployment = DeploymentResource::new(Some(DeploymentSetup {
+            zones: vec![(0, zone)],
+            ready_players: vec![1],
+            placements: vec![DeploymentPlacement {
+                unit: 1,
+                position: XyPos::new(10.0, 10.0),
+            }],
+        }));
+
+        let inside = XyPos::new(50.0, 50.0);
+        assert_eq!(deployment.check_placement(&teams, 1, 1, inside), Ok(()));
+        assert_eq!(
+            deployment.check_placement(&teams, 1, 1, XyPos::new(150.0, 50.0)),
+            Err(DeploymentError::OutsideZone)
+        );
+        assert_eq!(
+            deployment.check_placement(&teams, 1, 2, inside),
+            Err(DeploymentError::NotOwner)
+        );
+        assert_eq!(
+            deployment.check_placement(&teams, 2, 2, inside),
+            Err(DeploymentError::NoZone)
+        );
+        assert!(!deployment.everyone_ready());
+
+        // placements are replayed once, by scenario index
+        let (first, second) = (Entity::new(7), Entity::new(8));
+        deployment.scenario_units = vec![first, second];
+        let replayed = deployment.replay_commands(&|_| Some(1));
+        assert!(matches!(
+            replayed.as_slice(),
+            [DeploymentCommand::Place { player: 1, unit, .. }] if *unit == second
+        ));
+        assert!(deployment.replay_commands(&|_| Some(1)).is_empty());
+
+        deployment.record(first, inside);
+        assert_eq!(
+            deployment.recorded_setup().placements,
+            vec![DeploymentPlacement {
+                unit: 0,
+                position: inside
+            }]
+        );
+    }
+}
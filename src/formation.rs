@@ -0,0 +1,227 @@
+//! Unit formations: how many men wide a unit is, and how tightly they are packed.
+//!
+//! The formation drives the footprint (sprite and melee collider) of a unit, the slots
+//! that individual soldiers stand in, and how many men can fight at once. Soldiers fight
+//! whoever they are touching, so a wider formation puts more of them in contact, while
+//! single-body units have their melee output scaled by `frontage_factor`.
+
+use bevy::prelude::*;
+
+use bevy_rapier2d::physics::{ColliderHandleComponent, RigidBodyHandleComponent};
+use bevy_rapier2d::rapier::dynamics::RigidBodySet;
+use bevy_rapier2d::rapier::geometry::{ColliderBuilder, ColliderSet};
+use bevy_rapier2d::rapier::math::{Isometry, Vector};
+
+use crate::objectives::CapturePointComponent;
+use crate::physics::*;
+use crate::soldiers::*;
+use crate::units::{forget_unit_contacts, NearbyUnitsComponent};
+use crate::*;
+
+/// single-body units are treated as a block of this many men for sizing their footprint
+pub const SINGLE_BODY_MEN: usize = 25;
+/// how many ranks deep can reach the enemy in melee
+pub const FIGHTING_RANKS: usize = 2;
+const LOOSE_SPACING: f32 = 10.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FormationSpacing {
+    Tight,
+    Loose,
+}
+
+pub struct FormationComponent {
+    /// number of men across the front of the unit
+    pub files: usize,
+    pub spacing: FormationSpacing,
+    /// the facing the unit should turn to once it reaches its destination
+    pub final_facing: Option<XyPos>,
+    /// footprint the collider was last built for, so we only rebuild it when it changes
+    applied_size: Option<XyPos>,
+}
+
+impl FormationSpacing {
+    /// distance between the centres of neighbouring men
+    pub fn distance(&self) -> f32 {
+        match self {
+            FormationSpacing::Tight => SOLDIER_SPACING,
+            FormationSpacing::Loose => LOOSE_SPACING,
+        }
+    }
+
+    pub fn toggle(&self) -> Self {
+        match self {
+            FormationSpacing::Tight => FormationSpacing::Loose,
+            FormationSpacing::Loose => FormationSpacing::Tight,
+        }
+    }
+}
+
+impl FormationComponent {
+    /// a square-ish block for `men` soldiers
+    pub fn square(men: usize) -> Self {
+        FormationComponent {
+            files: (men as f32).sqrt().ceil().max(1.0) as usize,
+            spacing: FormationSpacing::Tight,
+            final_facing: None,
+            applied_size: None,
+        }
+    }
+
+    /// number of men across the front, which can't be more than there are men in the unit
+    pub fn frontage_men(&self, men: usize) -> usize {
+        self.files.min(men).max(1)
+    }
+
+    /// number of ranks deep
+    pub fn ranks(&self, men: usize) -> usize {
+        let files = self.frontage_men(men);
+        ((men + files - 1) / files).max(1)
+    }
+
+    /// width and depth of the unit footprint
+    pub fn size(&self, men: usize) -> XyPos {
+        let spacing = self.spacing.distance();
+        XyPos::new(
+            self.frontage_men(men) as f32 * spacing,
+            self.ranks(men) as f32 * spacing,
+        )
+    }
+
+    /// sets the frontage from a width in world units, eg from a right-click-drag
+    pub fn set_frontage(&mut self, width: f32) {
+        self.files = (width / self.spacing.distance()).round().max(1.0) as usize;
+    }
+
+    /// how many men can fight at once, only the first few ranks can reach the enemy
+    pub fn fighting_men(&self, men: usize) -> usize {
+        (self.frontage_men(men) * FIGHTING_RANKS).min(men)
+    }
+
+    /// how much melee a unit puts out compared to the same men in a square block
+    pub fn frontage_factor(&self, men: usize) -> f32 {
+        if men == 0 {
+            return 0.0;
+        }
+        self.fighting_men(men) as f32 / FormationComponent::square(men).fighting_men(men) as f32
+    }
+
+    /// offset of a soldier slot from the unit centre, with the first rank at the front of the unit
+    pub fn slot_offset(&self, slot: usize, men: usize, facing: XyPos) -> XyPos {
+        let files = self.frontage_men(men);
+        let ranks = self.ranks(men);
+        let spacing = self.spacing.distance();
+
+        let file = slot % files;
+        let rank = slot / files;
+
+        let sideways = (file as f32 - (files - 1) as f32 / 2.0) * spacing;
+        let forwards = ((ranks - 1) as f32 / 2.0 - rank as f32) * spacing;
+
+        let right = XyPos::new(facing.y, -facing.x);
+        right * sideways + facing * forwards
+    }
+}
+
+/// number of men used for formation calculations
+pub fn headcount(soldiers: Option<&SoldiersComponent>) -> usize {
+    soldiers
+        .map(|s| s.soldiers.len())
+        .unwrap_or(SINGLE_BODY_MEN)
+}
+
+/// the body rotation that points a units front along `facing`
+pub fn facing_angle(facing: XyPos) -> f32 {
+    facing.y.atan2(facing.x) - std::f32::consts::FRAC_PI_2
+}
+
+/// rotates unit bodies to their facing, and resizes their footprint to match their formation
+pub fn formation_system(
+    mut bodies: ResMut<RigidBodySet>,
+    mut colliders: ResMut<ColliderSet>,
+    e_to_ct: Res<EntityToColliderType>,
+    mut unit_query: Query<(
+        Entity,
+        &mut FormationComponent,
+        &FacingComponent,
+        &mut Sprite,
+        &RigidBodyHandleComponent,
+        &mut ColliderHandleComponent,
+        Option<&SoldiersComponent>,
+    )>,
+    mut nearbys: Query<(Entity, &mut NearbyUnitsComponent)>,
+    mut soldier_query: Query<&mut SoldierComponent>,
+    mut capture_points: Query<&mut CapturePointComponent>,
+) {
+    for (entity, mut formation, facing, mut sprite, body_handle, mut collider_handle, soldiers) in
+        unit_query.iter_mut()
+    {
+        let body = bodies.get_mut(body_handle.handle()).expect("body");
+        let pos = Isometry::new(body.position().translation.vector, facing_angle(facing.0));
+        body.set_position(pos, true);
+
+        let size = formation.size(headcount(soldiers));
+        if formation.applied_size == Some(size) {
+            continue;
+        }
+
+        sprite.size = Vec2::new(size.x, size.y);
+
+        // missile units use their collider for firing range, so only melee footprints are resized
+        if let Some(ColliderType::Melee) = e_to_ct.0.get(&entity) {
+            forget_unit_contacts(
+                entity,
+                &mut nearbys,
+                &mut soldier_query,
+                &mut capture_points,
+            );
+            colliders.remove(collider_handle.handle(), &mut *bodies, true);
+            let collider = ColliderBuilder::cuboid(size.x / 2.0, size.y / 2.0)
+                .sensor(true)
+                .build();
+            let handle = colliders.insert(collider, body_handle.handle(), &mut *bodies);
+            *collider_handle = ColliderHandleComponent::from(handle);
+        }
+
+        formation.applied_size = Some(size);
+    }
+}
+
+/// splits a right-click-drag line between `count` units, giving the centre, facing and
+/// frontage of each units slice of the line. Units face to the left of the drag direction.
+pub fn split_formation_line(start: XyPos, end: XyPos, count: usize) -> Vec<(XyPos, XyPos, f32)> {
+    let line = end - start;
+    let length = line.length();
+    if count == 0 || length == 0.0 {
+        return vec![];
+    }
+
+    let along = line / length;
+    let facing = XyPos::new(-along.y, along.x);
+    let frontage = length / count as f32;
+
+    (0..count)
+        .map(|i| {
+            let centre = start + along * (frontage * (i as f32 + 0.5));
+            (centre, facing, frontage)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_formation_shape() {
+        let mut formation = FormationComponent::square(16);
+        assert_eq!(formation.files, 4);
+        assert_eq!(formation.ranks(16), 4);
+        assert_eq!(formation.fighting_men(16), 8);
+
+        formation.set_frontage(8.0 * SOLDIER_SPACING);
+        assert_eq!(formation.ranks(16), 2);
+        assert_eq!(formation.fighting_men(16), 16);
+        assert!(formation.frontage_factor(16) > 1.0);
+    }
+}
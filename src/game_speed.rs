@@ -6,6 +6,8 @@ pub struct GameSpeed {
     elapsed_time: f32,
     delta: f32,
     is_paused: bool,
+    /// set once the battle is over. Unlike a pause, this can't be undone.
+    is_stopped: bool,
 }
 
 impl Default for GameSpeed {
@@ -15,14 +17,15 @@ impl Default for GameSpeed {
             elapsed_time: 0.0,
             delta: 0.0,
             is_paused: false,
+            is_stopped: false,
         }
     }
 }
 
 impl GameSpeed {
-    /// Returns true if the game is currently paused
+    /// Returns true if the game is currently paused, or has been stopped
     pub fn is_paused(&self) -> bool {
-        self.is_paused
+        self.is_paused || self.is_stopped
     }
 
     pub fn toggle_pause(&mut self) {
@@ -36,6 +39,15 @@ impl GameSpeed {
     pub fn unpause(&mut self) {
         self.is_paused = false;
     }
+
+    /// Stops the simulation for good, eg once the battle is over
+    pub fn stop(&mut self) {
+        self.is_stopped = true;
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.is_stopped
+    }
 }
 
 /// Add this on a new entity (with no other components) to request a game speed change
@@ -1,21 +1,46 @@
 #![deny(unreachable_patterns)]
 #![feature(const_fn)]
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
+use crate::abilities::Ability;
 use crate::game_speed::{GameSpeed, GameSpeedRequest};
 use crate::physics::ContactType;
+use crate::targeting::TargetingPolicy;
 use crate::teams::*;
 
+pub mod abilities;
+pub mod agent;
 pub mod combat;
+pub mod deployment;
+pub mod formation;
 pub mod game_speed;
+pub mod line_of_sight;
+pub mod objectives;
 pub mod physics;
+pub mod projectiles;
+pub mod pursuit;
+pub mod scenario;
+pub mod scripting;
+pub mod soldiers;
+pub mod spawn;
+pub mod targeting;
 pub mod teams;
+pub mod triggers;
 pub mod ui;
 pub mod units;
 pub mod user_input;
+pub mod victory;
 
 const WALKING_SPEED_FACTOR: f32 = 0.5;
 const MAX_HP: f32 = 100.0;
+const MAX_MORALE: f32 = 100.0;
+/// units rout when their morale drops to this fraction of max
+const ROUT_MORALE_RATIO: f32 = 0.2;
+/// movement is simulated in fixed steps of this many seconds
+pub const MOVEMENT_TIMESTEP: f32 = 1.0 / 60.0;
+/// units closer than this to their destination are considered to have arrived
+const ARRIVAL_DISTANCE: f32 = 1.0;
 
 pub type XyPos = Vec2;
 
@@ -24,20 +49,53 @@ pub struct DebugTimer(pub Timer);
 #[derive(Default)]
 pub struct UnitInteractionState {
     pub event_reader: EventReader<UnitInteractionEvent>,
+    /// commands and other non-contact events sent while the game was paused, handled once
+    /// it resumes
+    pub held_events: Vec<UnitInteractionEvent>,
 }
 
 #[derive(Clone, Copy, Debug)]
 pub enum UnitInteractionEvent {
     Proximity(ContactType),
-    Ui(Entity, UnitUiCommand),
+    /// a command from a player (human, agent or otherwise) for one of their units
+    Ui(PlayerId, Entity, UnitUiCommand),
     UnitDied(Entity),
     UnitWaypointReached(Entity),
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandError {
+    /// players can only command their own units
+    NotOwner { owner: PlayerId },
+    /// the unit doesn't exist, eg it has died
+    UnknownUnit,
+}
+
+/// sent when a unit command is refused
+#[derive(Clone, Copy, Debug)]
+pub struct CommandRejectedEvent {
+    pub player: PlayerId,
+    pub unit: Entity,
+    pub cmd: UnitUiCommand,
+    pub error: CommandError,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MissileType {
     Bow,
     Javelin,
     Sling,
+    /// heavy throwing spears carried by legionaries, thrown just before contact
+    Pilum,
+}
+
+/// how a missile unit spreads out its shots
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FireMode {
+    /// the whole unit looses together, then reloads together
+    Volley,
+    /// men shoot as they are ready, so shots are spread evenly over the reload
+    Skirmish,
 }
 
 pub struct MissileStats {
@@ -45,6 +103,11 @@ pub struct MissileStats {
     pub current_ammunition: usize,
     pub range: f32,
     pub type_: MissileType,
+    pub fire_mode: FireMode,
+    /// seconds until the next shot can be taken
+    reload_remaining: f32,
+    /// seconds of aiming needed before the first shot after moving
+    aim_remaining: f32,
 }
 
 pub enum MissileWeaponComponent {
@@ -57,16 +120,29 @@ pub struct UnitComponent {
     pub current_command: UnitUserCommand,
     pub state: UnitState,
     max_speed: f32,
+    /// how quickly the unit gets up to speed, in units/s^2
+    acceleration: f32,
+    /// how quickly the unit can brake, in units/s^2
+    deceleration: f32,
+    /// maximum rate the unit can wheel around, in radians/s
+    turn_rate: f32,
+    /// multiplier on speed from the currently active ability
+    speed_modifier: f32,
     is_selected: bool,
     pub player_id: PlayerId,
     pub unit_type: UnitType,
     pub is_running: bool,
-    /// "guard mode" determines if the current unit will persue fleeing units if they
-    /// attempt to disengage melee
+    /// "guard mode" determines if the current unit will hold its ground, rather than
+    /// persue fleeing units if they attempt to disengage melee
     pub guard_mode_enabled: bool,
     /// "fire at will" determines if the unit will automatically use ranged projectiles
     /// at any enemy unit that enters its firing range
     pub fire_at_will: bool,
+    /// set by an attack order for units with a secondary missile weapon: throw one volley at
+    /// the target before charging in. Cleared once thrown, or by the next order.
+    pub throw_secondary: bool,
+    /// how the unit picks between targets in missile range
+    pub targeting_policy: TargetingPolicy,
     pub remaining_ammo: usize,
 }
 
@@ -75,15 +151,34 @@ pub struct HealthComponent {
     max_health: f32,
 }
 
+pub struct MoraleComponent {
+    current_morale: f32,
+    max_morale: f32,
+}
+
 /// should contain read-only stats
+#[derive(Clone)]
 pub struct CombatComponent {
     armour: f32,
     ap_damage: f32,
     melee_attack: f32,
     melee_defence: f32,
     normal_damage: f32,
+    /// percentage chance to block missiles that hit the unit from the front
+    shield: f32,
+    /// seconds spent winding up a melee attack before it lands
+    wind_up: f32,
+    /// seconds spent recovering after a melee attack before the next one can start
+    recovery: f32,
 }
 
+/// the direction the unit is facing, always normalized
+pub struct FacingComponent(pub XyPos);
+
+/// the current forward speed of the unit, along its facing
+#[derive(Default)]
+pub struct VelocityComponent(pub f32);
+
 pub enum WaypointComponent {
     None,
     Position(XyPos),
@@ -95,7 +190,7 @@ pub enum AttackType {
     Ranged,
 }
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum UnitType {
     MeleeCalvary,
     ShockCalvary,
@@ -157,24 +252,51 @@ impl UnitState {
     }
 }
 
-/// possible actions given to the unit by the user
-#[derive(Clone, Copy, Debug)]
+/// possible actions given to the unit by the user. Commands with a target only make sense in
+/// a running battle, so they can't be saved in a scenario.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum UnitUiCommand {
+    #[serde(skip)]
     Attack(Entity, UnitUiSpeedCommand),
+    /// attack with a secondary missile weapon, eg throw pila
+    #[serde(skip)]
+    AttackSecondary(Entity),
     Move(XyPos, UnitUiSpeedCommand),
+    /// move to `position` and form up with the given frontage width, facing `facing`
+    Formation {
+        position: XyPos,
+        facing: XyPos,
+        frontage: f32,
+        speed: UnitUiSpeedCommand,
+    },
+    ToggleSpacing,
+    ToggleFireMode,
     ToggleSpeed,
+    ActivateAbility(Ability),
+    ToggleAbility(Ability),
+    DeactivateAbility,
+    SetTargetingPolicy(TargetingPolicy),
     ToggleGuardMode,
     ToggleFireAtWill,
     Stop,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum UnitUiSpeedCommand {
     Run,
     Walk,
 }
 
 impl UnitComponent {
+    /// routing, or breaking off under orders. Fleeing units get chased, see `pursuit`
+    pub fn is_fleeing(&self, morale: Option<&MoraleComponent>) -> bool {
+        let disengaging = matches!(
+            (&self.state, &self.current_command),
+            (UnitState::Moving, UnitUserCommand::Move(_))
+        );
+        disengaging || morale.map(|m| m.is_routing()).unwrap_or(false)
+    }
+
     pub fn ui_state(&self) -> UnitUiState {
         match &self.state {
             UnitState::Melee(_) => UnitUiState::Melee,
@@ -213,12 +335,25 @@ impl UnitComponent {
         self.max_speed
     }
 
+    pub fn acceleration(&self) -> f32 {
+        self.acceleration
+    }
+
+    pub fn deceleration(&self) -> f32 {
+        self.deceleration
+    }
+
+    pub fn turn_rate(&self) -> f32 {
+        self.turn_rate
+    }
+
     pub fn current_speed(&self) -> f32 {
-        if self.is_running {
+        let speed = if self.is_running {
             self.max_speed
         } else {
             self.max_speed * WALKING_SPEED_FACTOR
-        }
+        };
+        speed * self.speed_modifier
     }
 
     pub fn default_from_type(
@@ -228,27 +363,62 @@ impl UnitComponent {
         let unit = match unit_type {
             UnitType::MeleeInfantry => UnitComponent {
                 max_speed: 50.0,
+                acceleration: 25.0,
+                deceleration: 60.0,
+                turn_rate: 3.0,
                 unit_type,
                 player_id,
                 ..UnitComponent::default()
             },
             UnitType::MissileInfantry => UnitComponent {
                 max_speed: 80.0,
+                acceleration: 40.0,
+                deceleration: 80.0,
+                turn_rate: 3.0,
+                unit_type,
+                player_id,
+                ..UnitComponent::default()
+            },
+            UnitType::SpearInfantry | UnitType::PikeInfantry => UnitComponent {
+                max_speed: 45.0,
+                acceleration: 20.0,
+                deceleration: 50.0,
+                turn_rate: 2.0,
+                unit_type,
+                player_id,
+                ..UnitComponent::default()
+            },
+            UnitType::ShockInfantry => UnitComponent {
+                max_speed: 60.0,
+                acceleration: 30.0,
+                deceleration: 60.0,
+                turn_rate: 3.0,
                 unit_type,
                 player_id,
                 ..UnitComponent::default()
             },
-            _ => unimplemented!(),
+            UnitType::MeleeCalvary | UnitType::ShockCalvary | UnitType::MissileCalvary => {
+                UnitComponent {
+                    max_speed: 120.0,
+                    acceleration: 60.0,
+                    deceleration: 40.0,
+                    turn_rate: 1.2,
+                    unit_type,
+                    player_id,
+                    ..UnitComponent::default()
+                }
+            }
         };
 
         let missile = match unit_type {
-            UnitType::MissileInfantry | UnitType::MissileCalvary => {
-                MissileWeaponComponent::Primary(MissileStats {
-                    max_ammunition: 500,
-                    current_ammunition: 500,
-                    range: 100.0,
-                    type_: MissileType::Bow,
-                })
+            UnitType::MissileInfantry => {
+                MissileWeaponComponent::Primary(MissileStats::new(MissileType::Bow, 500))
+            }
+            UnitType::MissileCalvary => {
+                MissileWeaponComponent::Primary(MissileStats::new(MissileType::Javelin, 40))
+            }
+            UnitType::MeleeInfantry => {
+                MissileWeaponComponent::Secondary(MissileStats::new(MissileType::Pilum, 4))
             }
             _ => MissileWeaponComponent::None,
         };
@@ -268,7 +438,159 @@ impl UnitComponent {
     }
 }
 
+impl CombatComponent {
+    pub fn default_from_type(unit_type: UnitType) -> Self {
+        let (wind_up, recovery) = match unit_type {
+            UnitType::ShockInfantry => (0.4, 0.8),
+            UnitType::SpearInfantry | UnitType::PikeInfantry => (0.7, 1.0),
+            UnitType::MeleeCalvary | UnitType::ShockCalvary | UnitType::MissileCalvary => {
+                (0.8, 1.2)
+            }
+            UnitType::MeleeInfantry | UnitType::MissileInfantry => (0.6, 0.9),
+        };
+
+        CombatComponent {
+            wind_up,
+            recovery,
+            ..CombatComponent::default()
+        }
+    }
+
+    /// seconds between the start of one melee attack and the next
+    pub fn attack_interval(&self) -> f32 {
+        self.wind_up + self.recovery
+    }
+}
+
+impl HealthComponent {
+    pub fn new(max_health: f32) -> Self {
+        HealthComponent {
+            max_health,
+            current_health: max_health,
+        }
+    }
+}
+
+impl FacingComponent {
+    /// turns the unit to point along `direction`. Zero-length directions are ignored
+    pub fn face(&mut self, direction: XyPos) {
+        if direction.length_squared() > 0.0 {
+            self.0 = direction.normalize();
+        }
+    }
+}
+
+impl MoraleComponent {
+    pub fn apply_penalty(&mut self, penalty: f32) {
+        self.current_morale = (self.current_morale - penalty).max(0.0);
+    }
+
+    pub fn ratio(&self) -> f32 {
+        self.current_morale / self.max_morale
+    }
+
+    pub fn is_routing(&self) -> bool {
+        self.ratio() <= ROUT_MORALE_RATIO
+    }
+
+    /// regains morale, up to the max. Units that are routing have broken and don't rally
+    pub fn recover(&mut self, amount: f32) {
+        if !self.is_routing() {
+            self.current_morale = (self.current_morale + amount).min(self.max_morale);
+        }
+    }
+}
+
+impl UnitType {
+    pub fn is_cavalry(&self) -> bool {
+        match self {
+            UnitType::MeleeCalvary | UnitType::ShockCalvary | UnitType::MissileCalvary => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_infantry(&self) -> bool {
+        !self.is_cavalry()
+    }
+
+    pub fn is_missile(&self) -> bool {
+        match self {
+            UnitType::MissileCalvary | UnitType::MissileInfantry => true,
+            _ => false,
+        }
+    }
+}
+
+impl FireMode {
+    pub fn toggle(&self) -> Self {
+        match self {
+            FireMode::Volley => FireMode::Skirmish,
+            FireMode::Skirmish => FireMode::Volley,
+        }
+    }
+}
+
+impl MissileStats {
+    /// a full load of `ammunition`, with the range of the missile type
+    pub fn new(type_: MissileType, ammunition: usize) -> Self {
+        let profile = type_.profile();
+        MissileStats {
+            max_ammunition: ammunition,
+            current_ammunition: ammunition,
+            range: profile.range,
+            type_,
+            fire_mode: FireMode::Volley,
+            reload_remaining: 0.0,
+            aim_remaining: profile.aim_time,
+        }
+    }
+
+    /// advances the reload and aim timers by `dt` seconds. Moving throws off the aim, so it
+    /// has to start again once the unit stops.
+    pub fn tick(&mut self, dt: f32, moving: bool) {
+        self.reload_remaining = (self.reload_remaining - dt).max(0.0);
+        if moving {
+            self.aim_remaining = self.type_.profile().aim_time;
+        } else {
+            self.aim_remaining = (self.aim_remaining - dt).max(0.0);
+        }
+    }
+
+    pub fn is_ready_to_fire(&self) -> bool {
+        self.current_ammunition > 0 && self.reload_remaining <= 0.0 && self.aim_remaining <= 0.0
+    }
+
+    /// takes a shot, using up ammunition and starting the reload.
+    /// Returns the number of missiles launched.
+    pub fn fire(&mut self) -> usize {
+        let profile = self.type_.profile();
+        let (shots, reload) = match self.fire_mode {
+            FireMode::Volley => (profile.volley_size, profile.reload_time),
+            FireMode::Skirmish => (1, profile.reload_time / profile.volley_size as f32),
+        };
+
+        let shots = shots.min(self.current_ammunition);
+        self.current_ammunition -= shots;
+        self.reload_remaining = reload;
+        shots
+    }
+}
+
 impl MissileWeaponComponent {
+    pub fn stats(&self) -> Option<&MissileStats> {
+        match self {
+            MissileWeaponComponent::Primary(s) | MissileWeaponComponent::Secondary(s) => Some(s),
+            MissileWeaponComponent::None => None,
+        }
+    }
+
+    pub fn stats_mut(&mut self) -> Option<&mut MissileStats> {
+        match self {
+            MissileWeaponComponent::Primary(s) | MissileWeaponComponent::Secondary(s) => Some(s),
+            MissileWeaponComponent::None => None,
+        }
+    }
+
     pub fn is_missile_attack_available(&self) -> bool {
         if let MissileWeaponComponent::Primary(stats) | MissileWeaponComponent::Secondary(stats) =
             self
@@ -278,15 +600,6 @@ impl MissileWeaponComponent {
             false
         }
     }
-
-    pub fn use_ammo(&mut self) {
-        match self {
-            MissileWeaponComponent::Primary(s) | MissileWeaponComponent::Secondary(s) => {
-                s.current_ammunition -= 1;
-            }
-            _ => log::error!("using ammo without a weapon???"),
-        }
-    }
 }
 
 impl Default for UnitComponent {
@@ -295,12 +608,18 @@ impl Default for UnitComponent {
             current_command: UnitUserCommand::None_,
             state: UnitState::Idle,
             max_speed: 50.0,
+            acceleration: 25.0,
+            deceleration: 60.0,
+            turn_rate: 3.0,
+            speed_modifier: 1.0,
             is_selected: false,
             is_running: false,
             guard_mode_enabled: false,
             unit_type: UnitType::MeleeInfantry,
             remaining_ammo: 10,
             fire_at_will: true,
+            throw_secondary: false,
+            targeting_policy: TargetingPolicy::default(),
             player_id: 0,
         }
     }
@@ -321,6 +640,15 @@ impl Default for HealthComponent {
     }
 }
 
+impl Default for MoraleComponent {
+    fn default() -> Self {
+        MoraleComponent {
+            max_morale: MAX_MORALE,
+            current_morale: MAX_MORALE,
+        }
+    }
+}
+
 impl Default for CombatComponent {
     fn default() -> Self {
         CombatComponent {
@@ -329,10 +657,19 @@ impl Default for CombatComponent {
             melee_attack: 30.0,
             melee_defence: 30.0,
             normal_damage: 25.0,
+            shield: 25.0,
+            wind_up: 0.6,
+            recovery: 0.9,
         }
     }
 }
 
+impl Default for FacingComponent {
+    fn default() -> Self {
+        FacingComponent(XyPos::new(1.0, 0.0))
+    }
+}
+
 impl Default for MissileWeaponComponent {
     fn default() -> Self {
         MissileWeaponComponent::None
@@ -0,0 +1,224 @@
+//! Line of sight for missile units.
+//!
+//! Direct fire (javelins, pila) needs a clear line to the target, and is blocked by any unit
+//! or obstacle in the way. Arcing fire (bows, slings) can be lobbed over units, but shooting
+//! over friendlies costs accuracy. Obstacles block both.
+
+use bevy::prelude::*;
+
+use crate::teams::TeamsResource;
+use crate::units::NearbyUnitsComponent;
+use crate::*;
+
+/// scatter multiplier for arcing fire that has to go over friendly units
+pub const OVER_FRIENDLIES_SCATTER_FACTOR: f32 = 1.5;
+
+/// anything on the battlefield that blocks line of sight, eg rocks or buildings.
+/// Uses the sprite as its (axis-aligned) footprint.
+#[derive(Debug, Default)]
+pub struct ObstacleComponent;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineOfSight {
+    Clear,
+    /// arcing fire can still shoot, but less accurately
+    OverFriendlies,
+    Blocked,
+}
+
+/// line of sight from a missile unit to each of the units within its firing range
+#[derive(Debug, Default)]
+pub struct LineOfSightComponent {
+    pub blocked: Vec<Entity>,
+    pub over_friendlies: Vec<Entity>,
+}
+
+impl LineOfSightComponent {
+    pub fn to(&self, target: Entity) -> LineOfSight {
+        if self.blocked.contains(&target) {
+            LineOfSight::Blocked
+        } else if self.over_friendlies.contains(&target) {
+            LineOfSight::OverFriendlies
+        } else {
+            LineOfSight::Clear
+        }
+    }
+
+    /// targets that can be shot at
+    pub fn visible(&self, targets: &Vec<Entity>) -> Vec<Entity> {
+        targets
+            .iter()
+            .filter(|e| !self.blocked.contains(e))
+            .cloned()
+            .collect()
+    }
+
+    /// how much worse than normal shots at `target` scatter
+    pub fn scatter_factor(&self, target: Entity) -> f32 {
+        if self.to(target) == LineOfSight::OverFriendlies {
+            OVER_FRIENDLIES_SCATTER_FACTOR
+        } else {
+            1.0
+        }
+    }
+}
+
+/// returns true if the line from `start` to `end` passes through the footprint of a unit
+/// centred at `centre`, facing along `facing`
+pub fn segment_intersects_footprint(
+    start: XyPos,
+    end: XyPos,
+    centre: XyPos,
+    facing: XyPos,
+    size: Vec2,
+) -> bool {
+    // move into the footprint's frame, where it is an axis-aligned box
+    let right = XyPos::new(facing.y, -facing.x);
+    let to_local = |p: XyPos| {
+        let relative = p - centre;
+        XyPos::new(relative.dot(right), relative.dot(facing))
+    };
+    let a = to_local(start);
+    let d = to_local(end) - a;
+
+    let mut t_min: f32 = 0.0;
+    let mut t_max: f32 = 1.0;
+    for &(p, dp, half) in [(a.x, d.x, size.x / 2.0), (a.y, d.y, size.y / 2.0)].iter() {
+        if dp.abs() < f32::EPSILON {
+            if p.abs() > half {
+                return false;
+            }
+        } else {
+            let t1 = (-half - p) / dp;
+            let t2 = (half - p) / dp;
+            t_min = t_min.max(t1.min(t2));
+            t_max = t_max.min(t1.max(t2));
+            if t_min > t_max {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// works out which targets each missile unit can see
+pub fn line_of_sight_system(
+    game_speed: Res<GameSpeed>,
+    teams: Res<TeamsResource>,
+    mut shooters: Query<(
+        Entity,
+        &UnitComponent,
+        &MissileWeaponComponent,
+        &NearbyUnitsComponent,
+        &mut LineOfSightComponent,
+    )>,
+    units: Query<(
+        Entity,
+        &UnitComponent,
+        &Transform,
+        &Sprite,
+        &FacingComponent,
+    )>,
+    obstacles: Query<(&ObstacleComponent, &Transform, &Sprite)>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    for (shooter, unit, missile, nearbys, mut los) in shooters.iter_mut() {
+        let arcing = match missile.stats() {
+            Some(stats) => stats.type_.profile().arcing,
+            None => continue,
+        };
+
+        los.blocked.clear();
+        los.over_friendlies.clear();
+
+        let from = match units.get_component::<Transform>(shooter) {
+            Ok(t) => XyPos::new(t.translation.x, t.translation.y),
+            Err(_) => continue,
+        };
+
+        for target in nearbys.missile_range().iter().cloned() {
+            let to = match units.get_component::<Transform>(target) {
+                Ok(t) => XyPos::new(t.translation.x, t.translation.y),
+                Err(_) => continue,
+            };
+
+            let behind_obstacle = obstacles.iter().any(|(_, transform, sprite)| {
+                segment_intersects_footprint(
+                    from,
+                    to,
+                    (transform.translation.x, transform.translation.y).into(),
+                    XyPos::new(0.0, 1.0),
+                    sprite.size,
+                )
+            });
+
+            let in_the_way: Vec<bool> = units
+                .iter()
+                .filter(|(e, _, _, _, _)| *e != shooter && *e != target)
+                .filter(|(_, _, transform, sprite, facing)| {
+                    segment_intersects_footprint(
+                        from,
+                        to,
+                        (transform.translation.x, transform.translation.y).into(),
+                        facing.0,
+                        sprite.size,
+                    )
+                })
+                .map(|(_, other, _, _, _)| !teams.is_foe(other.player_id, unit.player_id))
+                .collect();
+
+            if behind_obstacle || (!arcing && !in_the_way.is_empty()) {
+                los.blocked.push(target);
+            } else if in_the_way.iter().any(|friendly| *friendly) {
+                los.over_friendlies.push(target);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_segment_intersects_footprint() {
+        let centre = XyPos::new(10.0, 0.0);
+        let facing = XyPos::new(1.0, 0.0);
+        // 4 wide across the facing, 2 deep along it
+        let size = Vec2::new(4.0, 2.0);
+
+        let start = XyPos::new(0.0, 0.0);
+        assert!(segment_intersects_footprint(
+            start,
+            XyPos::new(20.0, 0.0),
+            centre,
+            facing,
+            size
+        ));
+        assert!(segment_intersects_footprint(
+            start,
+            XyPos::new(20.0, 3.0),
+            centre,
+            facing,
+            size
+        ));
+        assert!(!segment_intersects_footprint(
+            start,
+            XyPos::new(20.0, 5.0),
+            centre,
+            facing,
+            size
+        ));
+        // stops short
+        assert!(!segment_intersects_footprint(
+            start,
+            XyPos::new(8.0, 0.0),
+            centre,
+            facing,
+            size
+        ));
+    }
+}
@@ -5,9 +5,7 @@ use std::collections::HashMap;
 
 use bevy::{prelude::*, render::pass::ClearColor};
 
-use bevy_rapier2d::physics::{
-    ColliderHandleComponent, RapierPhysicsPlugin, RigidBodyHandleComponent,
-};
+use bevy_rapier2d::physics::RapierPhysicsPlugin;
 use bevy_rapier2d::rapier::dynamics::RigidBodyBuilder;
 use bevy_rapier2d::rapier::geometry::ColliderBuilder;
 
@@ -15,16 +13,54 @@ use bevy_rapier2d::render::RapierRenderPlugin;
 
 
 
+use tntw::abilities::*;
+use tntw::agent::*;
 use tntw::combat::*;
+use tntw::deployment::*;
+use tntw::formation::*;
+use tntw::line_of_sight::*;
+use tntw::objectives::*;
 use tntw::physics::*;
+use tntw::projectiles::*;
+use tntw::pursuit::*;
+use tntw::scenario::*;
+use tntw::scripting::*;
+use tntw::soldiers::*;
+use tntw::spawn::*;
+use tntw::targeting::*;
 use tntw::teams::*;
+use tntw::triggers::*;
 use tntw::ui;
 use tntw::units::*;
 use tntw::user_input;
+use tntw::victory::*;
 use tntw::*;
 
 fn main() {
     env_logger::init();
+
+    let scenario = match std::env::args().nth(1) {
+        Some(path) if path.ends_with(".ron") => match Scenario::load(&path) {
+            Ok(scenario) => scenario,
+            Err(e) => {
+                eprintln!("can't load scenario {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        Some(name) => match Scenario::from_name(&name) {
+            Some(scenario) => scenario,
+            None => {
+                eprintln!(
+                    "unknown scenario {}, expected one of: {}, or a .ron scenario file",
+                    name,
+                    Scenario::NAMES.join(", ")
+                );
+                std::process::exit(1);
+            }
+        },
+        None => Scenario::default(),
+    };
+
     App::build()
         .add_plugins(DefaultPlugins)
         .add_plugin(RapierPhysicsPlugin)
@@ -35,27 +71,70 @@ fn main() {
         .add_resource(EntityToBodyHandle(HashMap::new()))
         .add_resource(EntityToColliderType(HashMap::new()))
         .add_resource(DebugTimer(Timer::from_seconds(1.0, true)))
+        .add_resource(LocalPlayer(scenario.local_player))
+        .add_resource(BattleResource::new(scenario.victory_conditions.clone()))
+        .add_resource(DeploymentResource::new(scenario.deployment.clone()))
+        .add_resource(ScriptResource::load(&scenario.scripts, &scenario.teams))
+        .add_resource(scenario)
         .init_resource::<user_input::InputState>()
         .init_resource::<ui::SelectionMaterials>()
         .init_resource::<ui::HeathBarMaterials>()
         .init_resource::<TeamsResource>()
+        .add_resource(MatchupTable::load(MATCHUPS_PATH))
+        .init_resource::<ActiveMatchups>()
+        .init_resource::<ProjectileMaterials>()
+        .init_resource::<SpawnMaterials>()
+        .init_resource::<ObjectiveControl>()
+        .init_resource::<AgentObservation>()
         .add_event::<UnitInteractionEvent>()
+        .add_event::<CommandRejectedEvent>()
+        .add_event::<BattleOutcome>()
+        .add_event::<DeploymentCommand>()
+        .add_event::<CallReinforcements>()
+        .add_event::<ScenarioMessage>()
         .add_startup_system(setup.system())
         .add_system(bevy::input::system::exit_on_esc_system.system())
         .add_system(user_input::cursor_system.system())
         .add_system(user_input::input_system.system())
+        .add_system(deployment_input_system.system())
+        .add_system(deployment_system.system())
+        .add_system(reinforcement_system.system())
         .add_system(unit_event_system.system())
+        .add_system(ability_system.system())
+        .add_system(secondary_missile_range_system.system())
+        .add_system(missile_targeting_system.system())
+        .add_system(melee_targeting_system.system())
+        .add_system(line_of_sight_system.system())
         .add_system(unit_state_machine_system.system())
+        .add_system(pursuit_system.system())
         .add_system(unit_waypoint_system.system())
         .add_system(unit_movement_system.system())
+        .add_system(unit_facing_system.system())
+        .add_system(formation_system.system())
+        .add_system(soldier_movement_system.system())
         .add_system(body_to_entity_system.system())
         .add_system(remove_rigid_body_system.system())
         .add_system(physics_debug_system.system())
         .add_system(unit_melee_system.system())
+        .add_system(soldier_melee_system.system())
         .add_system(unit_missile_system.system())
+        .add_system(projectile_flight_system.system())
+        .add_system(projectile_impact_system.system())
+        .add_system(soldier_casualty_system.system())
+        .add_system(capture_point_system.system())
+        .add_system(trigger_system.system())
+        .add_system(reclassify_contacts_system.system())
+        .add_system(support_morale_system.system())
+        .add_system(script_system.system())
+        .add_system(victory_system.system())
+        .add_system(agent_observation_system.system())
         .add_system(ui::state_icon_system.system())
         .add_system(ui::selection_system.system())
         .add_system(ui::healthbar_system.system())
+        .add_system(ui::matchup_overlay_system.system())
+        .add_system(ui::battle_outcome_text_system.system())
+        .add_system(ui::message_text_system.system())
+        .add_system_to_stage(stage::PRE_UPDATE, clear_matchups_system.system())
         .add_system_to_stage(
             stage::POST_UPDATE,
             unit_proximity_interaction_system.system(),
@@ -66,8 +145,12 @@ fn main() {
 fn setup(
     mut commands: Commands,
     mut teams: ResMut<TeamsResource>,
+    scenario: Res<Scenario>,
+    mut e_to_ct: ResMut<EntityToColliderType>,
     selection_materials: Res<ui::SelectionMaterials>,
     healthbar_materials: Res<ui::HeathBarMaterials>,
+    spawn_materials: Res<SpawnMaterials>,
+    mut deployment: ResMut<DeploymentResource>,
     mut materials: ResMut<Assets<ColorMaterial>>,
     asset_server: Res<AssetServer>,
 ) {
@@ -77,6 +160,7 @@ fn setup(
         moving_fast: materials.add(asset_server.load("textures/move_fast.png").into()),
         melee: materials.add(asset_server.load("textures/swords.png").into()),
         firing: materials.add(asset_server.load("assets/textures/bow.png").into()), // UPDATED
+        ability: materials.add(asset_server.load("textures/sheid.png").into()),
     });
 
     // Add the game's entities to our world
@@ -85,86 +169,123 @@ fn setup(
         .spawn(Camera2dComponents::default())
         .spawn(UiCameraComponents::default());
 
-    let unit_start_positions = vec![
-        (UnitType::MissileInfantry, 1, 150.0, 0.0),
-        (UnitType::MeleeInfantry, 2, -150.0, 0.0),
-    ];
-
-    let unit_size = 30.0;
-    let state_icon_size = 12.0;
-
-    for (ut, player, x, y) in unit_start_positions.into_iter() {
-        let (unit, missile) = UnitComponent::default_from_type(ut, player);
-
-        let body = RigidBodyBuilder::new_dynamic()
-            .translation(x, y)
-            .can_sleep(false); // things start annoyingly asleep
-
-        // TODO add more colliders when bevy_rapier supports it.
-        // for now, missile units cant engage in melee
-        let collider = if let AttackType::Melee = &unit.primary_attack_type() {
-            ColliderBuilder::cuboid(unit_size / 2.0, unit_size / 2.0).sensor(true)
-        } else {
-            if let MissileWeaponComponent::Primary(stats) = &missile {
-                ColliderBuilder::ball(stats.range).sensor(true)
-            } else {
-                unimplemented!();
-            }
-        };
-
-        teams.add_player(player, player);
+    // matchup bonus debug overlay
+    commands
+        .spawn(TextComponents {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(5.0),
+                    left: Val::Px(5.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                style: TextStyle {
+                    font_size: 16.0,
+                    color: Color::BLACK,
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        })
+        .with(ui::MatchupOverlayText);
 
-        commands
-            .spawn(SpriteComponents {
-                material: selection_materials.normal.clone_weak().into(),
-                transform: Transform::from_translation(Vec3::new(x, y, 1.0)),
-                sprite: Sprite::new(Vec2::new(unit_size, unit_size)),
+    // shown when the battle ends
+    commands
+        .spawn(TextComponents {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(5.0),
+                    right: Val::Px(5.0),
+                    ..Default::default()
+                },
                 ..Default::default()
-            })
-            .with(unit)
-            .with(missile)
-            .with(WaypointComponent::default())
-            .with(HealthComponent::default())
-            .with(CombatComponent::default())
-            .with(NearbyUnitsComponent::default())
-            .with_bundle((body, collider))
-            // ui state icon
-            .with_children(|parent| {
-                parent.spawn(SpriteComponents {
-                    sprite: Sprite::new(Vec2::new(state_icon_size, state_icon_size)),
-                    material: selection_materials.normal.clone_weak().into(),
-                    global_transform: GlobalTransform::from_translation(Vec3::new(
-                        (unit_size / 2.0) + (state_icon_size / 2.0) + 5.0,
-                        (unit_size / 2.0) - (state_icon_size / 2.0),
-                        0.0,
-                    )),
-                    // .apply_non_uniform_scale(Vec3::new(ui::ICON_SCALE, ui::ICON_SCALE, ui::ICON_SCALE)),
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                style: TextStyle {
+                    font_size: 32.0,
+                    color: Color::BLACK,
                     ..Default::default()
-                });
-            })
-            // healthbar
-            .with_children(|parent| {
-                let xpos = 0.0;
-                let ypos = -(unit_size / 2.0) - 5.0;
-
-                // background
-                parent.spawn(SpriteComponents {
-                    material: healthbar_materials.background.clone_weak().into(),
-                    transform: Transform::from_translation(Vec3::new(xpos, ypos, 1.0)),
-                    sprite: Sprite::new(Vec2::new(unit_size, 5.0)),
+                },
+            },
+            ..Default::default()
+        })
+        .with(ui::BattleOutcomeText);
+
+    // scenario messages, eg tutorial instructions
+    commands
+        .spawn(TextComponents {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(5.0),
+                    left: Val::Px(5.0),
                     ..Default::default()
-                });
-                // foreground
-                parent.spawn(SpriteComponents {
-                    material: healthbar_materials.high.clone_weak().into(),
-                    transform: Transform::from_translation(Vec3::new(xpos, ypos, 2.0)),
-                    sprite: Sprite::new(Vec2::new(unit_size, 5.0)),
+                },
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                style: TextStyle {
+                    font_size: 24.0,
+                    color: Color::BLACK,
                     ..Default::default()
-                });
-            });
+                },
+            },
+            ..Default::default()
+        })
+        .with(ui::MessageText);
+
+    log::info!("starting scenario {}", scenario.name);
+    *teams = TeamsResource::from_setup(&scenario.teams);
+    let mut players = scenario.players();
+    players.push(scenario.local_player);
+    if let Err(e) = teams.validate(&players) {
+        panic!("Invalid team setup in scenario {}: {}", scenario.name, e);
+    }
+
+    // deployment placements refer to units by their index in the scenario, so every unit
+    // has to be there
+    for spawn in scenario.units.iter() {
+        let unit = spawn_unit(
+            &mut commands,
+            spawn,
+            &selection_materials,
+            &healthbar_materials,
+            &spawn_materials,
+            &mut e_to_ct,
+        )
+        .unwrap_or_else(|e| panic!("Invalid unit in scenario {}: {}", scenario.name, e));
+        deployment.scenario_units.push(unit);
     }
 
-    teams.free_for_all();
+    let capture_point_material = materials.add(Color::rgba(0.9, 0.8, 0.2, 0.4).into());
+    for spawn in scenario.capture_points.iter() {
+        let (x, y) = (spawn.position.x, spawn.position.y);
+        let body = RigidBodyBuilder::new_static().translation(x, y);
+        let collider = ColliderBuilder::ball(spawn.radius).sensor(true);
+
+        let point_e = commands
+            .spawn(SpriteComponents {
+                material: capture_point_material.clone(),
+                transform: Transform::from_translation(Vec3::new(x, y, 0.0)),
+                sprite: Sprite::new(Vec2::new(spawn.radius * 2.0, spawn.radius * 2.0)),
+                ..Default::default()
+            })
+            .with(CapturePointComponent::new(spawn.radius, spawn.capture_time))
+            .with_bundle((body, collider))
+            .current_entity()
+            .expect("Capture point entity");
+        e_to_ct.0.insert(point_e, ColliderType::CapturePoint);
+    }
 
     // set up cursor tracker
     let camera = Camera2dComponents::default();
@@ -0,0 +1,152 @@
+//! Capture points: objectives on the map that teams fight over.
+//!
+//! Each point has a sensor collider, so units coming and going are picked up through the
+//! normal proximity events. A unit counts as being on the point when its centre is within the
+//! radius and it isn't routing. A point is captured by units of a single side (a team and its
+//! allies) standing on it for the capture time; if more than one side is on it, it is contested
+//! and nothing changes. Allies don't take points off each other.
+
+use bevy::prelude::*;
+
+use crate::teams::*;
+use crate::victory::ObjectiveControl;
+use crate::*;
+
+#[derive(Debug)]
+pub struct CapturePointComponent {
+    pub radius: f32,
+    /// seconds a side needs to hold the point uncontested to capture it
+    pub capture_time: f32,
+    pub owner: Option<TeamId>,
+    /// the team currently capturing the point, and for how long they have been at it
+    pub progress: Option<(TeamId, f32)>,
+    pub contested: bool,
+    /// units whose colliders overlap the point
+    nearby_units: Vec<Entity>,
+}
+
+impl CapturePointComponent {
+    pub fn new(radius: f32, capture_time: f32) -> Self {
+        CapturePointComponent {
+            radius,
+            capture_time,
+            owner: None,
+            progress: None,
+            contested: false,
+            nearby_units: vec![],
+        }
+    }
+
+    pub fn nearby_units(&self) -> &Vec<Entity> {
+        &self.nearby_units
+    }
+
+    pub fn unit_entered(&mut self, unit: Entity) {
+        if !self.nearby_units.contains(&unit) {
+            self.nearby_units.push(unit);
+        }
+    }
+
+    pub fn unit_left(&mut self, unit: Entity) {
+        self.nearby_units.retain(|e| e != &unit);
+    }
+
+    /// updates capture progress given the sides standing on the point, see `TeamsResource::sides`
+    pub fn tick(&mut self, sides_present: &[Vec<TeamId>], dt: f32) {
+        self.contested = sides_present.len() > 1;
+
+        let side = match sides_present {
+            [side] if !side.is_empty() => side,
+            // empty or contested, so progress is frozen
+            _ => return,
+        };
+
+        if self
+            .owner
+            .map(|owner| side.contains(&owner))
+            .unwrap_or(false)
+        {
+            self.progress = None;
+            return;
+        }
+
+        // allies carry on each others captures
+        let (team, time) = match self.progress {
+            Some((capturing, time)) if side.contains(&capturing) => (capturing, time + dt),
+            _ => (side[0], dt),
+        };
+        if time >= self.capture_time {
+            log::info!("team {} captured an objective", team);
+            self.owner = Some(team);
+            self.progress = None;
+        } else {
+            self.progress = Some((team, time));
+        }
+    }
+}
+
+/// progresses captures, and publishes who owns each point
+pub fn capture_point_system(
+    time: Res<Time>,
+    game_speed: Res<GameSpeed>,
+    teams: Res<TeamsResource>,
+    mut control: ResMut<ObjectiveControl>,
+    mut points: Query<(Entity, &mut CapturePointComponent, &Transform)>,
+    units: Query<(&UnitComponent, &Transform, Option<&MoraleComponent>)>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    for (entity, mut point, transform) in points.iter_mut() {
+        let centre: XyPos = (transform.translation.x, transform.translation.y).into();
+
+        let teams_present: Vec<TeamId> = point
+            .nearby_units()
+            .iter()
+            .filter_map(|e| units.get(e.clone()).ok())
+            .filter(|(_, unit_transform, morale)| {
+                let pos: XyPos =
+                    (unit_transform.translation.x, unit_transform.translation.y).into();
+                (pos - centre).length() <= point.radius
+                    && !morale.map(|m| m.is_routing()).unwrap_or(false)
+            })
+            .filter_map(|(unit, _, _)| teams.team_of(unit.player_id).ok())
+            .collect();
+
+        point.tick(&teams.sides(&teams_present), time.delta_seconds());
+        control.0.insert(entity, point.owner);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_capture_point() {
+        let mut point = CapturePointComponent::new(50.0, 10.0);
+        point.tick(&[vec![1]], 6.0);
+        assert_eq!(point.progress, Some((1, 6.0)));
+
+        // contested points freeze
+        point.tick(&[vec![0], vec![1]], 6.0);
+        assert!(point.contested);
+        assert_eq!(point.progress, Some((1, 6.0)));
+
+        // allies on the point together don't contest it, and carry on the capture
+        point.tick(&[vec![1, 2]], 6.0);
+        assert!(!point.contested);
+        assert_eq!(point.owner, Some(1));
+
+        // nor can an ally take it
+        point.tick(&[vec![2]], 6.0);
+        assert_eq!(point.owner, Some(1));
+        assert_eq!(point.progress, None);
+
+        // someone else has to start from scratch
+        point.tick(&[vec![0]], 6.0);
+        assert_eq!(point.owner, Some(1));
+        assert_eq!(point.progress, Some((0, 6.0)));
+    }
+}
@@ -16,31 +16,80 @@ use std::collections::HashMap;
 pub enum ColliderType {
     Melee,
     FiringRange,
+    Soldier,
+    CapturePoint,
 }
 
+/// filled in by `body_to_entity_system` as bodies are created
 pub struct BodyHandleToEntity(pub HashMap<RigidBodyHandle, Entity>);
 pub struct EntityToBodyHandle(pub HashMap<Entity, RigidBodyHandle>);
 pub struct EntityToColliderType(pub HashMap<Entity, ColliderType>);
 
+use crate::soldiers::*;
+use crate::teams::*;
 use crate::*;
 
 #[derive(Debug, Copy, Clone)]
 pub enum ContactType {
     UnitUnitMeleeEnter(Entity, Entity),
     UnitUnitMeleeExit(Entity, Entity),
-    UnitFiringRangeEnter { range_of: Entity, target: Entity },
-    UnitFiringRangeExit { range_of: Entity, target: Entity },
+    /// two non-hostile units touching. They don't fight, but do know about each other.
+    UnitUnitFriendlyEnter(Entity, Entity),
+    UnitUnitFriendlyExit(Entity, Entity),
+    UnitFiringRangeEnter {
+        range_of: Entity,
+        target: Entity,
+    },
+    UnitFiringRangeExit {
+        range_of: Entity,
+        target: Entity,
+    },
+    /// a non-hostile unit in range. It can't be shot at, but is kept track of in case it
+    /// becomes an enemy.
+    UnitFiringRangeFriendlyEnter {
+        range_of: Entity,
+        target: Entity,
+    },
+    UnitFiringRangeFriendlyExit {
+        range_of: Entity,
+        target: Entity,
+    },
+    SoldierSoldierMeleeEnter(Entity, Entity),
+    SoldierSoldierMeleeExit(Entity, Entity),
+    /// a soldier touching a unit that isn't made up of soldiers
+    SoldierUnitMeleeEnter {
+        soldier: Entity,
+        unit: Entity,
+    },
+    SoldierUnitMeleeExit {
+        soldier: Entity,
+        unit: Entity,
+    },
+    /// a soldier touching a non-hostile soldier or single-body unit from another unit. Either
+    /// side may be the soldier.
+    SoldierFriendlyEnter(Entity, Entity),
+    SoldierFriendlyExit(Entity, Entity),
+    CapturePointEnter {
+        point: Entity,
+        unit: Entity,
+    },
+    CapturePointExit {
+        point: Entity,
+        unit: Entity,
+    },
 }
 
 pub fn unit_proximity_interaction_system(
     bh_to_e: Res<BodyHandleToEntity>,
     e_to_ct: Res<EntityToColliderType>,
-    bodies: ResMut<RigidBodySet>,
+    colliders: Res<ColliderSet>,
     events: Res<EventQueue>,
+    teams: Res<TeamsResource>,
     mut unit_events: ResMut<Events<UnitInteractionEvent>>,
     units: Query<&UnitComponent>,
+    soldiers: Query<&SoldierComponent>,
+    soldier_units: Query<&SoldiersComponent>,
 ) {
-    use ColliderType::*;
     // we can can ignore contact events because we are only using sensors, not
     // rigid-body contactors. Sensors only spawn proximity events.
     // while let Ok(contact_event) = events.contact_events.pop() {
@@ -53,64 +102,40 @@ pub fn unit_proximity_interaction_system(
     // prox events are triggered between sensors and colliders (sensor or not)
     while let Ok(prox_event) = events.proximity_events.pop() {
         // new_status is guaranteed to be != prev_status
-        match prox_event.new_status {
-            Proximity::Disjoint => {
-                let b1 = bodies.get(prox_event.collider1).unwrap();
-                let b2 = bodies.get(prox_event.collider2).unwrap();
-                let e1 = Entity::from_bits(b1.user_data as u64);
-                let e2 = Entity::from_bits(b2.user_data as u64);
-
-                if units.get_component::<UnitComponent>(e1).is_ok()
-                    && units.get_component::<UnitComponent>(e2).is_ok()
-                {
-                    match (e_to_ct.0.get(&e1).unwrap(), e_to_ct.0.get(&e2).unwrap()) {
-                        (Melee, Melee) => {
-                            contacts.push(ContactType::UnitUnitMeleeExit(e1, e2));
-                        }
-                        (Melee, FiringRange) => {
-                            contacts.push(ContactType::UnitFiringRangeExit {
-                                range_of: e2,
-                                target: e1,
-                            });
-                        }
-                        (FiringRange, Melee) => {
-                            contacts.push(ContactType::UnitFiringRangeExit {
-                                range_of: e1,
-                                target: e2,
-                            });
-                        }
-                        (FiringRange, FiringRange) => (),
-                    }
-                }
-            }
-            Proximity::Intersecting => {
-                let e1 = *(bh_to_e.0.get(&prox_event.collider1).expect("get"));
-                let e2 = *(bh_to_e.0.get(&prox_event.collider2).expect("get"));
-                if units.get_component::<UnitComponent>(e1).is_ok()
-                    && units.get_component::<UnitComponent>(e2).is_ok()
-                {
-                    match (e_to_ct.0.get(&e1).unwrap(), e_to_ct.0.get(&e2).unwrap()) {
-                        (Melee, Melee) => {
-                            contacts.push(ContactType::UnitUnitMeleeEnter(e1, e2));
-                        }
-                        (Melee, FiringRange) => {
-                            contacts.push(ContactType::UnitFiringRangeEnter {
-                                range_of: e2,
-                                target: e1,
-                            });
-                        }
-                        (FiringRange, Melee) => {
-                            contacts.push(ContactType::UnitFiringRangeEnter {
-                                range_of: e1,
-                                target: e2,
-                            });
-                        }
-                        (FiringRange, FiringRange) => (),
-                    }
-                }
-            }
+        let e_or_e = match prox_event.new_status {
+            Proximity::Disjoint => EnterOrExit::Exit,
+            Proximity::Intersecting => EnterOrExit::Enter,
             // ignore WithinMargin because we don't need any special behaviour for that case.
-            Proximity::WithinMargin => (),
+            Proximity::WithinMargin => continue,
+        };
+
+        // events are between colliders, so go through the body each one is attached to.
+        // Either side may have been despawned already, in which case there's nothing to do.
+        let entity_of = |collider| {
+            colliders
+                .get(collider)
+                .and_then(|c| bh_to_e.0.get(&c.parent()))
+                .cloned()
+        };
+        let (e1, e2) = match (
+            entity_of(prox_event.collider1),
+            entity_of(prox_event.collider2),
+        ) {
+            (Some(e1), Some(e2)) => (e1, e2),
+            _ => continue,
+        };
+
+        if let Some(contact) = classify_contact(
+            e1,
+            e2,
+            e_or_e,
+            &e_to_ct,
+            &teams,
+            &units,
+            &soldiers,
+            &soldier_units,
+        ) {
+            contacts.push(contact);
         }
     }
 
@@ -119,6 +144,146 @@ pub fn unit_proximity_interaction_system(
     }
 }
 
+/// helper function
+/// works out what kind of interaction a proximity event between two entities is, if any.
+/// Only enemies can fight or be shot at, but non-hostile contacts are reported too, so they
+/// can be re-sorted if the team relations change.
+fn classify_contact(
+    e1: Entity,
+    e2: Entity,
+    e_or_e: EnterOrExit,
+    e_to_ct: &EntityToColliderType,
+    teams: &TeamsResource,
+    units: &Query<&UnitComponent>,
+    soldiers: &Query<&SoldierComponent>,
+    soldier_units: &Query<&SoldiersComponent>,
+) -> Option<ContactType> {
+    use ColliderType::*;
+    use EnterOrExit::*;
+
+    let is_unit = |e: Entity| units.get_component::<UnitComponent>(e).is_ok();
+    // units made of soldiers fight through their soldiers, not their footprint
+    let is_single_body_unit =
+        |e: Entity| is_unit(e) && soldier_units.get_component::<SoldiersComponent>(e).is_err();
+    let soldier_unit = |e: Entity| {
+        soldiers
+            .get_component::<SoldierComponent>(e)
+            .ok()
+            .map(|s| s.unit)
+    };
+    // soldiers belong to the player that owns their unit
+    let player = |e: Entity| {
+        let unit = soldier_unit(e).unwrap_or(e);
+        units
+            .get_component::<UnitComponent>(unit)
+            .ok()
+            .map(|u| u.player_id)
+    };
+    let hostile = match (player(e1), player(e2)) {
+        (Some(p1), Some(p2)) => teams.is_foe(p1, p2),
+        _ => false,
+    };
+
+    let firing_range = |range_of: Entity, target: Entity| match (e_or_e, hostile) {
+        (Enter, true) => ContactType::UnitFiringRangeEnter { range_of, target },
+        (Exit, true) => ContactType::UnitFiringRangeExit { range_of, target },
+        (Enter, false) => ContactType::UnitFiringRangeFriendlyEnter { range_of, target },
+        (Exit, false) => ContactType::UnitFiringRangeFriendlyExit { range_of, target },
+    };
+    // soldiers of the same unit, or a soldier and its own unit, aren't contacts at all
+    let soldier_friendly = || {
+        let (u1, u2) = (
+            soldier_unit(e1).unwrap_or(e1),
+            soldier_unit(e2).unwrap_or(e2),
+        );
+        let single_or_soldier = |e: Entity| soldier_unit(e).is_some() || is_single_body_unit(e);
+        if u1 == u2 || !single_or_soldier(e1) || !single_or_soldier(e2) {
+            return None;
+        }
+        Some(match e_or_e {
+            Enter => ContactType::SoldierFriendlyEnter(e1, e2),
+            Exit => ContactType::SoldierFriendlyExit(e1, e2),
+        })
+    };
+
+    match (e_to_ct.0.get(&e1)?, e_to_ct.0.get(&e2)?) {
+        // capture points track every unit, whichever team it is on
+        (CapturePoint, Melee) | (CapturePoint, FiringRange) if is_unit(e2) => Some(match e_or_e {
+            Enter => ContactType::CapturePointEnter {
+                point: e1,
+                unit: e2,
+            },
+            Exit => ContactType::CapturePointExit {
+                point: e1,
+                unit: e2,
+            },
+        }),
+        (Melee, CapturePoint) | (FiringRange, CapturePoint) if is_unit(e1) => Some(match e_or_e {
+            Enter => ContactType::CapturePointEnter {
+                point: e2,
+                unit: e1,
+            },
+            Exit => ContactType::CapturePointExit {
+                point: e2,
+                unit: e1,
+            },
+        }),
+        (Melee, Melee) if is_unit(e1) && is_unit(e2) && hostile => Some(match e_or_e {
+            Enter => ContactType::UnitUnitMeleeEnter(e1, e2),
+            Exit => ContactType::UnitUnitMeleeExit(e1, e2),
+        }),
+        (Melee, Melee) if is_unit(e1) && is_unit(e2) => Some(match e_or_e {
+            Enter => ContactType::UnitUnitFriendlyEnter(e1, e2),
+            Exit => ContactType::UnitUnitFriendlyExit(e1, e2),
+        }),
+        (Melee, FiringRange) if is_unit(e1) && is_unit(e2) => Some(firing_range(e2, e1)),
+        (FiringRange, Melee) if is_unit(e1) && is_unit(e2) => Some(firing_range(e1, e2)),
+        (Soldier, Soldier) | (Soldier, Melee) | (Melee, Soldier) if !hostile => soldier_friendly(),
+        (Soldier, Soldier) if soldier_unit(e1)? != soldier_unit(e2)? => Some(match e_or_e {
+            Enter => ContactType::SoldierSoldierMeleeEnter(e1, e2),
+            Exit => ContactType::SoldierSoldierMeleeExit(e1, e2),
+        }),
+        (Soldier, Melee) if soldier_unit(e1)? != e2 && is_single_body_unit(e2) => {
+            Some(match e_or_e {
+                Enter => ContactType::SoldierUnitMeleeEnter {
+                    soldier: e1,
+                    unit: e2,
+                },
+                Exit => ContactType::SoldierUnitMeleeExit {
+                    soldier: e1,
+                    unit: e2,
+                },
+            })
+        }
+        (Melee, Soldier) if soldier_unit(e2)? != e1 && is_single_body_unit(e1) => {
+            Some(match e_or_e {
+                Enter => ContactType::SoldierUnitMeleeEnter {
+                    soldier: e2,
+                    unit: e1,
+                },
+                Exit => ContactType::SoldierUnitMeleeExit {
+                    soldier: e2,
+                    unit: e1,
+                },
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Records the rapier body of each newly spawned entity, so physics events can be mapped back
+/// to entities, and bodies can be cleaned up when entities despawn
+pub fn body_to_entity_system(
+    mut bh_to_e: ResMut<BodyHandleToEntity>,
+    mut e_to_bh: ResMut<EntityToBodyHandle>,
+    added: Query<(Entity, &RigidBodyHandleComponent), Added<RigidBodyHandleComponent>>,
+) {
+    for (entity, body_handle) in added.iter() {
+        bh_to_e.0.insert(body_handle.handle(), entity);
+        e_to_bh.0.insert(entity, body_handle.handle());
+    }
+}
+
 /// Detects when a RigidBodyHandle is removed from an entity, as it despawns
 /// And inform rapier about the removal
 pub fn remove_rigid_body_system(
@@ -182,12 +347,22 @@ pub enum EnterOrExit {
 impl ContactType {
     pub fn enter_or_exit(&self) -> EnterOrExit {
         match self {
-            ContactType::UnitFiringRangeEnter { .. } | ContactType::UnitUnitMeleeEnter(..) => {
-                EnterOrExit::Enter
-            }
-            ContactType::UnitFiringRangeExit { .. } | ContactType::UnitUnitMeleeExit(..) => {
-                EnterOrExit::Exit
-            }
+            ContactType::UnitFiringRangeEnter { .. }
+            | ContactType::UnitUnitMeleeEnter(..)
+            | ContactType::UnitUnitFriendlyEnter(..)
+            | ContactType::SoldierSoldierMeleeEnter(..)
+            | ContactType::SoldierUnitMeleeEnter { .. }
+            | ContactType::UnitFiringRangeFriendlyEnter { .. }
+            | ContactType::SoldierFriendlyEnter(..)
+            | ContactType::CapturePointEnter { .. } => EnterOrExit::Enter,
+            ContactType::UnitFiringRangeExit { .. }
+            | ContactType::UnitUnitMeleeExit(..)
+            | ContactType::UnitUnitFriendlyExit(..)
+            | ContactType::SoldierSoldierMeleeExit(..)
+            | ContactType::SoldierUnitMeleeExit { .. }
+            | ContactType::UnitFiringRangeFriendlyExit { .. }
+            | ContactType::SoldierFriendlyExit(..)
+            | ContactType::CapturePointExit { .. } => EnterOrExit::Exit,
         }
     }
 }
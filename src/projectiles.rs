@@ -0,0 +1,403 @@
+//! Arrows, javelins and slingstones in flight.
+//!
+//! Missile units launch projectiles at a point near their target, rather than damaging the
+//! target directly. Projectiles fly on a ballistic arc and hit whatever unit is at the landing
+//! point when they come down, friend or foe.
+
+use std::cmp::Ordering;
+
+use bevy::prelude::*;
+
+use crate::abilities::*;
+use crate::combat::*;
+use crate::soldiers::*;
+use crate::*;
+
+const GRAVITY: f32 = 200.0;
+const PROJECTILE_SIZE: f32 = 3.0;
+/// how much bigger a projectile is drawn per unit of height, so the arc is visible top-down
+const ARC_RENDER_SCALE: f32 = 0.02;
+
+/// how a type of missile behaves in flight and when it hits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MissileProfile {
+    pub range: f32,
+    /// seconds between volleys
+    pub reload_time: f32,
+    /// seconds spent aiming after stopping, before the first shot
+    pub aim_time: f32,
+    pub normal_damage: f32,
+    pub ap_damage: f32,
+    /// fraction of the targets armour that is ignored, eg blunt slingstones
+    pub armour_ignored: f32,
+    /// radius of the landing scatter per unit of distance to the target
+    pub scatter_per_distance: f32,
+    /// number of projectiles launched per volley
+    pub volley_size: usize,
+    /// horizontal speed, in units/s
+    pub speed: f32,
+    /// arcing missiles are lobbed, direct missiles fly flat at the target
+    pub arcing: bool,
+}
+
+pub struct ProjectileMaterials {
+    pub projectile: Handle<ColorMaterial>,
+}
+
+impl MissileType {
+    pub fn profile(&self) -> MissileProfile {
+        match self {
+            MissileType::Bow => MissileProfile {
+                range: 150.0,
+                reload_time: 5.0,
+                aim_time: 1.5,
+                normal_damage: 20.0,
+                ap_damage: 3.0,
+                armour_ignored: 0.0,
+                scatter_per_distance: 0.12,
+                volley_size: 3,
+                speed: 200.0,
+                arcing: true,
+            },
+            MissileType::Javelin => MissileProfile {
+                range: 40.0,
+                reload_time: 6.0,
+                aim_time: 0.5,
+                normal_damage: 10.0,
+                ap_damage: 20.0,
+                armour_ignored: 0.0,
+                scatter_per_distance: 0.05,
+                volley_size: 2,
+                speed: 120.0,
+                arcing: false,
+            },
+            MissileType::Sling => MissileProfile {
+                range: 110.0,
+                reload_time: 4.0,
+                aim_time: 1.0,
+                normal_damage: 18.0,
+                ap_damage: 2.0,
+                armour_ignored: 0.5,
+                scatter_per_distance: 0.09,
+                volley_size: 3,
+                speed: 180.0,
+                arcing: true,
+            },
+            MissileType::Pilum => MissileProfile {
+                range: 60.0,
+                reload_time: 4.0,
+                aim_time: 0.5,
+                normal_damage: 8.0,
+                ap_damage: 25.0,
+                armour_ignored: 0.0,
+                scatter_per_distance: 0.05,
+                volley_size: 2,
+                speed: 100.0,
+                arcing: false,
+            },
+        }
+    }
+}
+
+pub struct ProjectileComponent {
+    /// the unit that fired this projectile
+    pub source: Entity,
+    pub source_type: UnitType,
+    pub missile: MissileType,
+    pub origin: XyPos,
+    /// where the projectile will come down, including scatter
+    pub landing: XyPos,
+    pub flight_time: f32,
+    pub elapsed: f32,
+}
+
+impl ProjectileComponent {
+    /// launches a projectile at a random point on a target of `target_size` centred on `aim`,
+    /// so a volley spreads over the whole unit. The landing point is then scattered more the
+    /// further away the target is. `scatter_factor` makes shots less accurate, eg when shooting
+    /// over friendlies.
+    pub fn launch(
+        source: Entity,
+        source_type: UnitType,
+        missile: MissileType,
+        origin: XyPos,
+        aim: XyPos,
+        target_size: Vec2,
+        scatter_factor: f32,
+    ) -> Self {
+        let profile = missile.profile();
+        let distance = (aim - origin).length();
+
+        // the largest circle that fits inside the target footprint, whichever way it faces
+        let target_radius = target_size.x.min(target_size.y) / 2.0;
+        let aim = aim + random_in_circle(target_radius);
+        let landing =
+            aim + random_in_circle(distance * profile.scatter_per_distance * scatter_factor);
+
+        ProjectileComponent {
+            source,
+            source_type,
+            missile,
+            origin,
+            landing,
+            flight_time: (landing - origin).length() / profile.speed,
+            elapsed: 0.0,
+        }
+    }
+
+    /// how far through its flight the projectile is, from 0 to 1
+    fn progress(&self) -> f32 {
+        if self.flight_time <= 0.0 {
+            1.0
+        } else {
+            (self.elapsed / self.flight_time).min(1.0)
+        }
+    }
+
+    /// ground position under the projectile
+    pub fn position(&self) -> XyPos {
+        self.origin + (self.landing - self.origin) * self.progress()
+    }
+
+    /// height above the ground on a ballistic arc that lands after `flight_time`.
+    /// Direct missiles are treated as flying flat.
+    pub fn height(&self) -> f32 {
+        if !self.missile.profile().arcing {
+            return 0.0;
+        }
+        let s = self.progress();
+        GRAVITY * self.flight_time.powi(2) / 2.0 * s * (1.0 - s)
+    }
+
+    pub fn has_landed(&self) -> bool {
+        self.elapsed >= self.flight_time
+    }
+
+    /// true if this projectile comes down on a unit with the given footprint.
+    /// Units can't hit themselves.
+    pub fn hits(&self, unit: Entity, unit_pos: XyPos, facing: XyPos, size: Vec2) -> bool {
+        unit != self.source && is_point_in_footprint(self.landing, unit_pos, facing, size)
+    }
+}
+
+/// helper function
+/// uniformly random point within a circle around the origin
+fn random_in_circle(radius: f32) -> XyPos {
+    let r = radius * rand::random::<f32>().sqrt();
+    let angle = rand::random::<f32>() * 2.0 * std::f32::consts::PI;
+    XyPos::new(angle.cos(), angle.sin()) * r
+}
+
+impl FromResources for ProjectileMaterials {
+    fn from_resources(resources: &Resources) -> Self {
+        let mut materials = resources
+            .get_mut::<Assets<ColorMaterial>>()
+            .expect("Colour resource");
+        ProjectileMaterials {
+            projectile: materials.add(Color::rgb(0.3, 0.2, 0.1).into()),
+        }
+    }
+}
+
+pub fn spawn_projectile(
+    commands: &mut Commands,
+    materials: &ProjectileMaterials,
+    projectile: ProjectileComponent,
+) {
+    let origin = projectile.origin;
+    commands
+        .spawn(SpriteComponents {
+            material: materials.projectile.clone(),
+            transform: Transform::from_translation(Vec3::new(origin.x, origin.y, 5.0)),
+            sprite: Sprite::new(Vec2::new(PROJECTILE_SIZE, PROJECTILE_SIZE)),
+            ..Default::default()
+        })
+        .with(projectile);
+}
+
+/// moves projectiles along their flight path
+pub fn projectile_flight_system(
+    game_speed: Res<GameSpeed>,
+    time: Res<Time>,
+    mut query: Query<(&mut ProjectileComponent, &mut Transform)>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    for (mut projectile, mut transform) in query.iter_mut() {
+        projectile.elapsed += time.delta_seconds();
+
+        let pos = projectile.position();
+        transform.translation.x = pos.x;
+        transform.translation.y = pos.y;
+        transform.scale = Vec3::splat(1.0 + projectile.height() * ARC_RENDER_SCALE);
+    }
+}
+
+/// returns true if `point` is inside the footprint of a unit
+pub fn is_point_in_footprint(point: XyPos, unit_pos: XyPos, facing: XyPos, size: Vec2) -> bool {
+    let relative = point - unit_pos;
+    let right = XyPos::new(facing.y, -facing.x);
+    relative.dot(right).abs() <= size.x / 2.0 && relative.dot(facing).abs() <= size.y / 2.0
+}
+
+/// resolves projectiles that have landed against whatever unit is at the landing point
+pub fn projectile_impact_system(
+    mut commands: Commands,
+    game_speed: Res<GameSpeed>,
+    matchups: Res<MatchupTable>,
+    mut active_matchups: ResMut<ActiveMatchups>,
+    mut unit_events: ResMut<Events<UnitInteractionEvent>>,
+    projectiles: Query<(Entity, &ProjectileComponent)>,
+    units: Query<(
+        Entity,
+        &UnitComponent,
+        &CombatComponent,
+        &Transform,
+        &Sprite,
+        &FacingComponent,
+    )>,
+    mut health_query: Query<&mut HealthComponent>,
+    mut morale_query: Query<&mut MoraleComponent>,
+    soldier_units: Query<&SoldiersComponent>,
+    mut soldier_query: Query<&mut SoldierComponent>,
+    transforms: Query<&Transform>,
+    abilities: Query<&AbilitiesComponent>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    for (projectile_id, projectile) in projectiles.iter() {
+        if !projectile.has_landed() {
+            continue;
+        }
+        commands.despawn(projectile_id);
+
+        let landing = projectile.landing;
+
+        let hit = units
+            .iter()
+            .find(|(entity, _, _, transform, sprite, facing)| {
+                projectile.hits(
+                    *entity,
+                    (transform.translation.x, transform.translation.y).into(),
+                    facing.0,
+                    sprite.size,
+                )
+            });
+
+        let (target, target_unit, target_combat, target_transform, _, target_facing) = match hit {
+            Some(hit) => hit,
+            // missed everything
+            None => continue,
+        };
+
+        let target_combat = effective_combat(&abilities, target, target_combat);
+
+        let bonus = matchups.bonus(projectile.source_type, target_unit.unit_type);
+        if !bonus.is_none() {
+            active_matchups.0.push(ActiveMatchup {
+                attacker: projectile.source,
+                defender: target,
+                attacker_type: projectile.source_type,
+                defender_type: target_unit.unit_type,
+                bonus,
+            });
+        }
+
+        let direction = attack_direction(
+            (
+                target_transform.translation.x,
+                target_transform.translation.y,
+            )
+                .into(),
+            target_facing.0,
+            projectile.origin,
+        );
+
+        if calc_shield_block(&target_combat, direction) {
+            continue;
+        }
+
+        let damage = calc_missile_damage(&projectile.missile.profile(), &target_combat, &bonus);
+        apply_direction_morale_penalty(&mut morale_query, target, direction);
+
+        if let Ok(target_soldiers) = soldier_units.get_component::<SoldiersComponent>(target) {
+            // the soldier closest to where it came down takes the hit
+            let casualty = target_soldiers
+                .soldiers
+                .iter()
+                .filter_map(|s| {
+                    transforms
+                        .get_component::<Transform>(s.clone())
+                        .ok()
+                        .map(|t| {
+                            let pos: XyPos = (t.translation.x, t.translation.y).into();
+                            (s.clone(), (pos - landing).length_squared())
+                        })
+                })
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+                .map(|(s, _)| s);
+
+            if let Some(casualty) = casualty {
+                soldier_query
+                    .get_component_mut::<SoldierComponent>(casualty)
+                    .unwrap()
+                    .current_health -= damage;
+            }
+        } else {
+            let mut target_heath = health_query
+                .get_component_mut::<HealthComponent>(target)
+                .unwrap();
+            target_heath.current_health -= damage;
+
+            if target_heath.current_health < 0.0 {
+                log::info!("unit dead!");
+                unit_events.send(UnitInteractionEvent::UnitDied(target));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_projectile_impact() {
+        let archers = Entity::new(1);
+        let target = Entity::new(2);
+        let target_pos = XyPos::new(100.0, 0.0);
+        let facing = XyPos::new(-1.0, 0.0);
+        let size = Vec2::new(40.0, 20.0);
+
+        // without scatter, every shot lands somewhere on the target
+        for _ in 0..20 {
+            let projectile = ProjectileComponent::launch(
+                archers,
+                UnitType::MissileInfantry,
+                MissileType::Bow,
+                XyPos::new(0.0, 0.0),
+                target_pos,
+                size,
+                0.0,
+            );
+            assert!(projectile.hits(target, target_pos, facing, size));
+            assert!(!projectile.hits(archers, target_pos, facing, size));
+        }
+
+        // aiming well clear of the target misses it
+        let projectile = ProjectileComponent::launch(
+            archers,
+            UnitType::MissileInfantry,
+            MissileType::Bow,
+            XyPos::new(0.0, 0.0),
+            XyPos::new(100.0, 50.0),
+            size,
+            0.0,
+        );
+        assert!(!projectile.hits(target, target_pos, facing, size));
+    }
+}
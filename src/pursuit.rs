@@ -0,0 +1,158 @@
+//! Chasing down enemies that break off from melee.
+//!
+//! When an enemy leaves a units melee range while fleeing (routing, or moving away under
+//! orders), units with guard mode off chase after it, up to a leash distance from where the
+//! chase started. Units in guard mode hold their ground instead.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::units::NearbyUnitsComponent;
+use crate::*;
+
+/// how far a unit will chase a fleeing enemy before giving up
+const PURSUIT_LEASH: f32 = 200.0;
+
+#[derive(Debug, Default)]
+pub struct PursuitComponent {
+    /// the fleeing unit being chased
+    pub target: Option<Entity>,
+    /// where the chase started, for the leash
+    pub origin: XyPos,
+    /// who the unit was fighting last tick, to detect disengages
+    last_melee_target: Option<Entity>,
+}
+
+impl PursuitComponent {
+    /// stops chasing, eg because the player gave the unit a new order
+    pub fn cancel(&mut self) {
+        self.target = None;
+        // so a unit ordered out of melee doesn't start chasing whoever it was fighting
+        self.last_melee_target = None;
+    }
+
+    /// starts a pursuit if the enemy we were fighting last tick broke off, and gives up on
+    /// one once the leash runs out or the target stops fleeing. Only touches the units
+    /// command if it is still the one the pursuit gave it.
+    pub fn update(
+        &mut self,
+        unit: &mut UnitComponent,
+        pos: XyPos,
+        melee_range: &[Entity],
+        is_fleeing: &dyn Fn(&Entity) -> bool,
+    ) {
+        if let Some(target) = self.target {
+            let leashed = (pos - self.origin).length() > PURSUIT_LEASH;
+            if leashed || !is_fleeing(&target) {
+                log::debug!("giving up pursuit");
+                self.target = None;
+                if matches!(unit.current_command, UnitUserCommand::AttackMelee(e) if e == target) {
+                    unit.current_command = UnitUserCommand::None_;
+                    unit.is_running = false;
+                }
+            }
+        } else if let Some(last) = self.last_melee_target {
+            if !melee_range.contains(&last) && is_fleeing(&last) {
+                if unit.guard_mode_enabled {
+                    // hold position
+                    if let UnitUserCommand::AttackMelee(_) = unit.current_command {
+                        unit.current_command = UnitUserCommand::None_;
+                    }
+                } else {
+                    log::debug!("pursuing fleeing enemy");
+                    self.target = Some(last);
+                    self.origin = pos;
+                    unit.current_command = UnitUserCommand::AttackMelee(last);
+                    unit.is_running = true;
+                }
+            }
+        }
+
+        self.last_melee_target = match unit.state {
+            UnitState::Melee(target) => target,
+            _ => None,
+        };
+    }
+}
+
+/// detects enemies disengaging from melee, and starts and stops pursuits
+pub fn pursuit_system(
+    game_speed: Res<GameSpeed>,
+    mut units: Query<(
+        Entity,
+        &mut UnitComponent,
+        &Transform,
+        &NearbyUnitsComponent,
+        &mut PursuitComponent,
+        Option<&MoraleComponent>,
+    )>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    let fleeing: HashMap<Entity, bool> = units
+        .iter()
+        .map(|(entity, unit, _, _, _, morale)| (entity, unit.is_fleeing(morale)))
+        .collect();
+    let is_fleeing = |e: &Entity| fleeing.get(e).cloned().unwrap_or(false);
+
+    for (_, mut unit, transform, nearbys, mut pursuit, _) in units.iter_mut() {
+        let pos: XyPos = (transform.translation.x, transform.translation.y).into();
+        pursuit.update(&mut unit, pos, nearbys.melee_range(), &is_fleeing);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pursuit() {
+        let enemy = Entity::new(1);
+        let fleeing = |_: &Entity| true;
+        let rallied = |_: &Entity| false;
+        let fighting = |pursuit: &mut PursuitComponent, unit: &mut UnitComponent| {
+            unit.state = UnitState::Melee(Some(enemy));
+            pursuit.update(unit, XyPos::new(0.0, 0.0), &[enemy], &fleeing);
+            unit.state = UnitState::Idle;
+        };
+
+        // the enemy breaks off, so we chase it
+        let mut unit = UnitComponent::default();
+        let mut pursuit = PursuitComponent::default();
+        fighting(&mut pursuit, &mut unit);
+        pursuit.update(&mut unit, XyPos::new(0.0, 0.0), &[], &fleeing);
+        assert_eq!(pursuit.target, Some(enemy));
+        assert!(matches!(unit.current_command, UnitUserCommand::AttackMelee(e) if e == enemy));
+
+        // until the leash runs out
+        pursuit.update(
+            &mut unit,
+            XyPos::new(PURSUIT_LEASH + 1.0, 0.0),
+            &[],
+            &fleeing,
+        );
+        assert_eq!(pursuit.target, None);
+        assert!(matches!(unit.current_command, UnitUserCommand::None_));
+
+        // guard mode holds its ground
+        let mut unit = UnitComponent::default();
+        unit.guard_mode_enabled = true;
+        let mut pursuit = PursuitComponent::default();
+        fighting(&mut pursuit, &mut unit);
+        pursuit.update(&mut unit, XyPos::new(0.0, 0.0), &[], &fleeing);
+        assert_eq!(pursuit.target, None);
+
+        // giving up doesn't clear an order the player gave during the chase
+        let mut unit = UnitComponent::default();
+        let mut pursuit = PursuitComponent::default();
+        fighting(&mut pursuit, &mut unit);
+        pursuit.update(&mut unit, XyPos::new(0.0, 0.0), &[], &fleeing);
+        unit.current_command = UnitUserCommand::Move(XyPos::new(50.0, 50.0));
+        pursuit.update(&mut unit, XyPos::new(0.0, 0.0), &[], &rallied);
+        assert_eq!(pursuit.target, None);
+        assert!(matches!(unit.current_command, UnitUserCommand::Move(_)));
+    }
+}
@@ -0,0 +1,470 @@
+//! Battle setups: who is fighting who, and what units they start with.
+//!
+//! The scenario is picked by name on the command line (eg `cargo run -- 2v2`), or loaded from
+//! a RON file (eg `cargo run -- assets/scenarios/ambush.ron`), and defaults to a 1v1 skirmish.
+//! Scenarios are saved in the same format, which is how deployments are kept for replays.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::deployment::{DeploymentSetup, DeploymentZone};
+use crate::scripting::ScriptSetup;
+use crate::teams::*;
+use crate::triggers::*;
+use crate::victory::VictoryCondition;
+use crate::*;
+
+/// where battles are saved once deployment is over, so they can be replayed
+pub const REPLAY_DIR: &str = "replays";
+
+/// a unit to spawn at the start of a battle
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UnitSpawn {
+    pub unit_type: UnitType,
+    pub player: PlayerId,
+    pub position: XyPos,
+    /// number of individual soldiers. 0 means a single-body unit.
+    #[serde(default)]
+    pub soldiers: usize,
+    /// lets triggers and scripts refer to this particular unit
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// the name a unit was given in the scenario
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnitNameComponent(pub String);
+
+/// an objective to place at the start of a battle
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CapturePointSpawn {
+    pub position: XyPos,
+    pub radius: f32,
+    /// seconds to capture
+    pub capture_time: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ReinforcementArrival {
+    /// this many seconds into the battle
+    AtTime(f32),
+    /// when a `CallReinforcements` event is sent for this group, eg by a trigger
+    OnCall,
+}
+
+/// a group of units that arrives partway through the battle, usually at a map edge
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Reinforcement {
+    pub arrival: ReinforcementArrival,
+    pub units: Vec<UnitSpawn>,
+}
+
+/// everything optional can be left out of a scenario file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Scenario {
+    pub name: String,
+    pub teams: TeamSetup,
+    /// the player controlled from the mouse and keyboard
+    pub local_player: PlayerId,
+    /// the battle ends when any of these are met
+    #[serde(default = "Scenario::default_victory_conditions")]
+    pub victory_conditions: Vec<VictoryCondition>,
+    pub units: Vec<UnitSpawn>,
+    #[serde(default)]
+    pub capture_points: Vec<CapturePointSpawn>,
+    /// if set, the battle starts with a deployment phase
+    #[serde(default)]
+    pub deployment: Option<DeploymentSetup>,
+    #[serde(default)]
+    pub reinforcements: Vec<Reinforcement>,
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+    /// Rhai scripts to run, eg for scripted opponents
+    #[serde(default)]
+    pub scripts: Vec<ScriptSetup>,
+}
+
+#[derive(Debug)]
+pub enum ScenarioError {
+    Io(std::io::Error),
+    /// the file isn't a valid scenario, or the scenario can't be saved, eg because a trigger
+    /// issues a command with a target
+    Ron(ron::Error),
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScenarioError::Io(e) => write!(f, "{}", e),
+            ScenarioError::Ron(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ScenarioError {}
+
+impl From<std::io::Error> for ScenarioError {
+    fn from(e: std::io::Error) -> Self {
+        ScenarioError::Io(e)
+    }
+}
+
+impl From<ron::Error> for ScenarioError {
+    fn from(e: ron::Error) -> Self {
+        ScenarioError::Ron(e)
+    }
+}
+
+impl UnitSpawn {
+    pub fn new(unit_type: UnitType, player: PlayerId, x: f32, y: f32, soldiers: usize) -> Self {
+        UnitSpawn {
+            unit_type,
+            player,
+            position: XyPos::new(x, y),
+            soldiers,
+            name: None,
+        }
+    }
+
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+}
+
+impl CapturePointSpawn {
+    pub fn new(x: f32, y: f32, radius: f32, capture_time: f32) -> Self {
+        CapturePointSpawn {
+            position: XyPos::new(x, y),
+            radius,
+            capture_time,
+        }
+    }
+}
+
+impl Scenario {
+    /// the names `from_name` knows
+    pub const NAMES: [&'static str; 7] = [
+        "skirmish", "1v1", "2v2", "1v1v1", "hill", "tutorial", "scripted",
+    ];
+
+    /// looks up one of the built-in scenarios
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "skirmish" | "1v1" => Some(Scenario::skirmish()),
+            "2v2" => Some(Scenario::two_v_two()),
+            "1v1v1" => Some(Scenario::three_way()),
+            "hill" => Some(Scenario::king_of_the_hill()),
+            "tutorial" => Some(Scenario::tutorial()),
+            "scripted" => Some(Scenario::scripted()),
+            _ => None,
+        }
+    }
+
+    pub fn from_ron(source: &str) -> Result<Self, ron::Error> {
+        ron::de::from_str(source)
+    }
+
+    pub fn to_ron(&self) -> Result<String, ron::Error> {
+        ron::ser::to_string_pretty(self, PrettyConfig::default())
+    }
+
+    /// loads a scenario file, either written by hand or saved by `save`
+    pub fn load(path: &str) -> Result<Self, ScenarioError> {
+        let source = fs::read_to_string(path)?;
+        Ok(Scenario::from_ron(&source)?)
+    }
+
+    /// saves the scenario to `path`, creating its directory if needed
+    pub fn save(&self, path: &str) -> Result<(), ScenarioError> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_ron()?)?;
+        Ok(())
+    }
+
+    /// the scenario with a different deployment, eg one recorded during a battle
+    pub fn with_deployment(&self, deployment: DeploymentSetup) -> Self {
+        Scenario {
+            deployment: Some(deployment),
+            ..self.clone()
+        }
+    }
+
+    /// where a replay of this scenario is saved
+    pub fn replay_path(&self) -> String {
+        format!("{}/{}.ron", REPLAY_DIR, self.name)
+    }
+
+    /// every player with units in the scenario, including ones that only arrive later
+    pub fn players(&self) -> Vec<PlayerId> {
+        let triggered = self
+            .triggers
+            .iter()
+            .flat_map(|t| t.actions.iter())
+            .flat_map(|a| match a {
+                TriggerAction::SpawnUnits(spawns) => spawns.clone(),
+                _ => vec![],
+            });
+        self.units
+            .iter()
+            .chain(self.reinforcements.iter().flat_map(|r| r.units.iter()))
+            .cloned()
+            .chain(triggered)
+            .map(|u| u.player)
+            .collect()
+    }
+
+    /// fight until one side is routed, or 10 minutes pass
+    pub fn default_victory_conditions() -> Vec<VictoryCondition> {
+        vec![
+            VictoryCondition::AllRouted,
+            VictoryCondition::TimeLimit(600.0),
+        ]
+    }
+
+    pub fn skirmish() -> Self {
+        use UnitType::*;
+        Scenario {
+            name: "skirmish".to_string(),
+            teams: TeamSetup::free_for_all(&[1, 2]),
+            local_player: 1,
+            victory_conditions: Scenario::default_victory_conditions(),
+            units: vec![
+                UnitSpawn::new(MissileInfantry, 1, 150.0, 0.0, 0),
+                UnitSpawn::new(MeleeCalvary, 1, 150.0, 100.0, 0),
+                UnitSpawn::new(ShockInfantry, 1, 150.0, -100.0, 16),
+                UnitSpawn::new(MeleeInfantry, 2, -150.0, 0.0, 16),
+                UnitSpawn::new(SpearInfantry, 2, -150.0, 100.0, 0),
+            ],
+            capture_points: vec![],
+            deployment: Some(DeploymentSetup {
+                zones: vec![
+                    (
+                        0,
+                        DeploymentZone::new(XyPos::new(50.0, -250.0), XyPos::new(300.0, 250.0)),
+                    ),
+                    (
+                        1,
+                        DeploymentZone::new(XyPos::new(-300.0, -250.0), XyPos::new(-50.0, 250.0)),
+                    ),
+                ],
+                ready_players: vec![1],
+                placements: vec![],
+            }),
+            reinforcements: vec![],
+            triggers: vec![],
+            scripts: vec![],
+        }
+    }
+
+    pub fn two_v_two() -> Self {
+        use UnitType::*;
+        Scenario {
+            name: "2v2".to_string(),
+            teams: TeamSetup::two_v_two([1, 2], [3, 4]),
+            local_player: 1,
+            victory_conditions: Scenario::default_victory_conditions(),
+            units: vec![
+                UnitSpawn::new(MeleeInfantry, 1, 150.0, 50.0, 16),
+                UnitSpawn::new(MissileInfantry, 1, 200.0, 50.0, 0),
+                UnitSpawn::new(SpearInfantry, 2, 150.0, -50.0, 0),
+                UnitSpawn::new(MeleeCalvary, 2, 200.0, -100.0, 0),
+                UnitSpawn::new(MeleeInfantry, 3, -150.0, 50.0, 16),
+                UnitSpawn::new(MissileInfantry, 3, -200.0, 50.0, 0),
+                UnitSpawn::new(ShockInfantry, 4, -150.0, -50.0, 0),
+                UnitSpawn::new(MissileCalvary, 4, -200.0, -100.0, 0),
+            ],
+            capture_points: vec![],
+            deployment: None,
+            reinforcements: vec![
+                Reinforcement {
+                    arrival: ReinforcementArrival::AtTime(60.0),
+                    units: vec![UnitSpawn::new(MeleeCalvary, 1, 400.0, 0.0, 0)],
+                },
+                Reinforcement {
+                    arrival: ReinforcementArrival::AtTime(60.0),
+                    units: vec![UnitSpawn::new(MeleeCalvary, 3, -400.0, 0.0, 0)],
+                },
+            ],
+            triggers: vec![],
+            scripts: vec![],
+        }
+    }
+
+    pub fn three_way() -> Self {
+        use UnitType::*;
+        Scenario {
+            name: "1v1v1".to_string(),
+            teams: TeamSetup::free_for_all(&[1, 2, 3]),
+            local_player: 1,
+            victory_conditions: Scenario::default_victory_conditions(),
+            units: vec![
+                UnitSpawn::new(MeleeInfantry, 1, 0.0, 200.0, 16),
+                UnitSpawn::new(MissileInfantry, 1, 50.0, 250.0, 0),
+                UnitSpawn::new(SpearInfantry, 2, -175.0, -100.0, 0),
+                UnitSpawn::new(MeleeCalvary, 2, -225.0, -150.0, 0),
+                UnitSpawn::new(ShockInfantry, 3, 175.0, -100.0, 16),
+                UnitSpawn::new(MissileInfantry, 3, 225.0, -150.0, 0),
+            ],
+            capture_points: vec![],
+            deployment: None,
+            reinforcements: vec![],
+            triggers: vec![],
+            scripts: vec![],
+        }
+    }
+
+    /// a 1v1 over a single capture point in the middle of the map
+    pub fn king_of_the_hill() -> Self {
+        let mut scenario = Scenario::skirmish();
+        scenario.name = "hill".to_string();
+        scenario
+            .victory_conditions
+            .insert(0, VictoryCondition::HoldObjectives(60.0));
+        scenario.capture_points = vec![CapturePointSpawn::new(0.0, 0.0, 60.0, 15.0)];
+        scenario
+    }
+
+    /// a 1v1 against an opponent played by a script
+    pub fn scripted() -> Self {
+        let mut scenario = Scenario::skirmish();
+        scenario.name = "scripted".to_string();
+        scenario.scripts = vec![ScriptSetup {
+            player: 2,
+            path: "assets/scripts/charge.rhai".to_string(),
+        }];
+        scenario
+    }
+
+    /// a scripted introduction to melee: fight off some raiders, then the cavalry that come to
+    /// help them
+    pub fn tutorial() -> Self {
+        use UnitType::*;
+        let say = |msg: &str| TriggerAction::ShowMessage(msg.to_string());
+        Scenario {
+            name: "tutorial".to_string(),
+            teams: TeamSetup::free_for_all(&[1, 2]),
+            local_player: 1,
+            victory_conditions: vec![VictoryCondition::Annihilation],
+            units: vec![
+                UnitSpawn::new(MeleeInfantry, 1, 150.0, 0.0, 16).named("legion"),
+                UnitSpawn::new(MeleeInfantry, 2, -150.0, 0.0, 0).named("raiders"),
+            ],
+            capture_points: vec![],
+            deployment: None,
+            reinforcements: vec![],
+            triggers: vec![
+                Trigger {
+                    condition: TriggerCondition::TimeElapsed(0.0),
+                    actions: vec![say(
+                        "Select your legion, then right click the enemy to attack",
+                    )],
+                },
+                Trigger {
+                    condition: TriggerCondition::HealthBelow(
+                        UnitSelector::Named("raiders".to_string()),
+                        0.5,
+                    ),
+                    actions: vec![
+                        say("Enemy cavalry! Turn to face them, or press 1 for shield wall"),
+                        TriggerAction::SpawnUnits(vec![UnitSpawn::new(
+                            MeleeCalvary,
+                            2,
+                            -400.0,
+                            200.0,
+                            0,
+                        )
+                        .named("cavalry")]),
+                    ],
+                },
+                Trigger {
+                    condition: TriggerCondition::UnitDied(UnitSelector::Named(
+                        "raiders".to_string(),
+                    )),
+                    actions: vec![say("The raiders are beaten, now finish off the cavalry")],
+                },
+                // the cavalry charge as soon as they arrive
+                Trigger {
+                    condition: TriggerCondition::UnitInArea(
+                        UnitSelector::Named("cavalry".to_string()),
+                        Area::new(XyPos::new(-500.0, 100.0), XyPos::new(-300.0, 300.0)),
+                    ),
+                    actions: vec![TriggerAction::IssueCommand(
+                        UnitSelector::Named("cavalry".to_string()),
+                        UnitUiCommand::Move(XyPos::new(150.0, 0.0), UnitUiSpeedCommand::Run),
+                    )],
+                },
+            ],
+            scripts: vec![],
+        }
+    }
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario::skirmish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::deployment::DeploymentPlacement;
+
+    #[test]
+    fn test_scenario_names() {
+        for name in Scenario::NAMES.iter() {
+            assert!(Scenario::from_name(name).is_some(), "{}", name);
+        }
+        assert!(Scenario::from_name("skirmsh").is_none());
+    }
+
+    #[test]
+    fn test_scenario_files() {
+        // the built-in scenarios all survive being saved and loaded again
+        for name in Scenario::NAMES.iter() {
+            let saved = Scenario::from_name(name).unwrap().to_ron().unwrap();
+            let loaded = Scenario::from_ron(&saved).unwrap();
+            assert_eq!(loaded.to_ron().unwrap(), saved, "{}", name);
+        }
+
+        // anything optional can be left out
+        let ambush = Scenario::from_ron(include_str!("../assets/scenarios/ambush.ron")).unwrap();
+        assert_eq!(ambush.name, "ambush");
+        assert_eq!(
+            ambush.victory_conditions,
+            Scenario::default_victory_conditions()
+        );
+        assert_eq!(ambush.units[0].name, Some("column".to_string()));
+        assert_eq!(ambush.units[1].soldiers, 0);
+        assert_eq!(
+            ambush.reinforcements[0].arrival,
+            ReinforcementArrival::AtTime(20.0)
+        );
+        assert!(ambush.deployment.is_none());
+        assert!(TeamsResource::from_setup(&ambush.teams)
+            .validate(&ambush.players())
+            .is_ok());
+
+        // recorded placements are kept with the rest of the setup
+        let placement = DeploymentPlacement {
+            unit: 1,
+            position: XyPos::new(100.0, 50.0),
+        };
+        let skirmish = Scenario::skirmish();
+        let replay = skirmish.with_deployment(DeploymentSetup {
+            placements: vec![placement],
+            ..skirmish.deployment.clone().unwrap()
+        });
+        let loaded = Scenario::from_ron(&replay.to_ron().unwrap()).unwrap();
+        let deployment = loaded.deployment.unwrap();
+        assert_eq!(deployment.placements, vec![placement]);
+        assert_eq!(deployment.ready_players, vec![1]);
+    }
+}
@@ -0,0 +1,426 @@
+//! Scripted opponents and scenario logic, written in Rhai.
+//!
+//! Each script is loaded from a file when the battle starts, and plays as one player, given to
+//! it as the `PLAYER` and `TEAM` constants. Every tick its `on_tick(units, time)` function is
+//! called with a view of every unit on the field. `print` and `debug` go to the log.
+//! Scripts can't touch the world directly; they can only call the functions registered here,
+//! which queue up commands. Commands are issued as the scripts player, so they are rejected
+//! like anyone else's for units it doesn't own. Operations per tick are capped so a bad script
+//! can't hang the game.
+//!
+//! See `assets/scripts/` for examples.
+
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use rhai::{Array, Dynamic, Engine, ImmutableString, Scope, AST, FLOAT, INT};
+use serde::{Deserialize, Serialize};
+
+use crate::abilities::*;
+use crate::teams::*;
+use crate::triggers::ScenarioMessage;
+use crate::victory::BattleResource;
+use crate::*;
+
+/// most operations a script can run each tick before it is stopped
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// a script to run for a scenario
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScriptSetup {
+    /// the player the script controls
+    pub player: PlayerId,
+    pub path: String,
+}
+
+/// the read-only view of a unit that scripts get
+#[derive(Clone, Debug)]
+pub struct UnitView {
+    pub id: INT,
+    pub player: INT,
+    /// -1 if the player isn't on a team
+    pub team: INT,
+    pub x: FLOAT,
+    pub y: FLOAT,
+    pub health: FLOAT,
+    pub routing: bool,
+    pub state: ImmutableString,
+    pub unit_type: ImmutableString,
+    /// names of the abilities the unit can activate right now
+    pub abilities: Array,
+    /// true if the unit has an ability that can be turned off
+    pub ability_active: bool,
+}
+
+/// what a script asked for during a tick
+#[derive(Debug, Default)]
+struct ScriptOutput {
+    commands: Vec<(Entity, UnitUiCommand)>,
+    messages: Vec<String>,
+    end_battle: Option<Option<TeamId>>,
+}
+
+struct LoadedScript {
+    player: PlayerId,
+    path: String,
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    output: Arc<Mutex<ScriptOutput>>,
+    /// the current team relations, for `is_foe`
+    relations: Arc<Mutex<TeamsResource>>,
+    /// set when the script errors, so it stops running instead of erroring every tick
+    failed: bool,
+}
+
+#[derive(Default)]
+pub struct ScriptResource {
+    scripts: Vec<LoadedScript>,
+}
+
+impl UnitView {
+    fn state_name(state: &UnitState) -> &'static str {
+        match state {
+            UnitState::Idle => "idle",
+            UnitState::Moving => "moving",
+            UnitState::Melee(_) => "melee",
+            UnitState::Firing(_) => "firing",
+            UnitState::FiringAndMoving(_) => "firing_and_moving",
+        }
+    }
+}
+
+/// helper function
+fn entity_from_id(id: INT) -> Entity {
+    Entity::from_bits(id as u64)
+}
+
+/// an engine with the script API bound to `output` and `relations`
+fn build_engine(
+    output: Arc<Mutex<ScriptOutput>>,
+    relations: Arc<Mutex<TeamsResource>>,
+    path: &str,
+) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(4096);
+    engine.set_max_array_size(10_000);
+
+    engine.register_type_with_name::<UnitView>("Unit");
+    engine.register_get("id", |u: &mut UnitView| u.id);
+    engine.register_get("player", |u: &mut UnitView| u.player);
+    engine.register_get("team", |u: &mut UnitView| u.team);
+    engine.register_get("x", |u: &mut UnitView| u.x);
+    engine.register_get("y", |u: &mut UnitView| u.y);
+    engine.register_get("health", |u: &mut UnitView| u.health);
+    engine.register_get("routing", |u: &mut UnitView| u.routing);
+    engine.register_get("state", |u: &mut UnitView| u.state.clone());
+    engine.register_get("unit_type", |u: &mut UnitView| u.unit_type.clone());
+    engine.register_get("abilities", |u: &mut UnitView| u.abilities.clone());
+    engine.register_get("ability_active", |u: &mut UnitView| u.ability_active);
+
+    // units without a team are nobodys foe, rather than everyones
+    engine.register_fn("is_foe", move |u1: UnitView, u2: UnitView| {
+        relations
+            .lock()
+            .map(|teams| teams.is_foe(u1.player as PlayerId, u2.player as PlayerId))
+            .unwrap_or(false)
+    });
+
+    let name = path.to_string();
+    engine.on_print(move |text: &str| log::info!("script {}: {}", name, text));
+    let name = path.to_string();
+    engine.on_debug(move |text: &str| log::debug!("script {}: {}", name, text));
+
+    let command = |output: &Arc<Mutex<ScriptOutput>>, id: INT, cmd: UnitUiCommand| {
+        if let Ok(mut out) = output.lock() {
+            out.commands.push((entity_from_id(id), cmd));
+        }
+    };
+
+    let out = output.clone();
+    engine.register_fn("move_to", move |id: INT, x: FLOAT, y: FLOAT| {
+        let pos = XyPos::new(x as f32, y as f32);
+        command(&out, id, UnitUiCommand::Move(pos, UnitUiSpeedCommand::Walk));
+    });
+    let out = output.clone();
+    engine.register_fn("run_to", move |id: INT, x: FLOAT, y: FLOAT| {
+        let pos = XyPos::new(x as f32, y as f32);
+        command(&out, id, UnitUiCommand::Move(pos, UnitUiSpeedCommand::Run));
+    });
+    let out = output.clone();
+    engine.register_fn("attack", move |id: INT, target: INT| {
+        let target = entity_from_id(target);
+        command(
+            &out,
+            id,
+            UnitUiCommand::Attack(target, UnitUiSpeedCommand::Walk),
+        );
+    });
+    let out = output.clone();
+    engine.register_fn("charge", move |id: INT, target: INT| {
+        let target = entity_from_id(target);
+        command(
+            &out,
+            id,
+            UnitUiCommand::Attack(target, UnitUiSpeedCommand::Run),
+        );
+    });
+    let out = output.clone();
+    engine.register_fn("stop", move |id: INT| {
+        command(&out, id, UnitUiCommand::Stop);
+    });
+    let out = output.clone();
+    engine.register_fn(
+        "use_ability",
+        move |id: INT, name: ImmutableString| match Ability::from_name(&name) {
+            Some(ability) => command(&out, id, UnitUiCommand::ActivateAbility(ability)),
+            None => log::warn!("script used unknown ability {}", name),
+        },
+    );
+    let out = output.clone();
+    engine.register_fn("stop_ability", move |id: INT| {
+        command(&out, id, UnitUiCommand::DeactivateAbility);
+    });
+    let out = output.clone();
+    engine.register_fn("message", move |text: ImmutableString| {
+        if let Ok(mut out) = out.lock() {
+            out.messages.push(text.to_string());
+        }
+    });
+    let out = output.clone();
+    engine.register_fn("end_battle", move |winner: INT| {
+        if let Ok(mut out) = out.lock() {
+            out.end_battle = Some(if winner < 0 {
+                None
+            } else {
+                Some(winner as TeamId)
+            });
+        }
+    });
+
+    engine
+}
+
+impl ScriptResource {
+    /// loads and compiles each script. Scripts that can't be loaded are logged and skipped.
+    pub fn load(setups: &[ScriptSetup], teams: &TeamSetup) -> Self {
+        let teams = TeamsResource::from_setup(teams);
+        let scripts = setups
+            .iter()
+            .filter_map(|setup| {
+                let source = std::fs::read_to_string(&setup.path)
+                    .map_err(|e| log::error!("can't read script {}: {}", setup.path, e))
+                    .ok()?;
+                ScriptResource::compile(setup, &source, &teams)
+            })
+            .collect();
+        ScriptResource { scripts }
+    }
+
+    /// helper function
+    fn compile(setup: &ScriptSetup, source: &str, teams: &TeamsResource) -> Option<LoadedScript> {
+        let output = Arc::new(Mutex::new(ScriptOutput::default()));
+        let relations = Arc::new(Mutex::new(teams.clone()));
+        let engine = build_engine(output.clone(), relations.clone(), &setup.path);
+        let ast = engine
+            .compile(source)
+            .map_err(|e| log::error!("can't compile script {}: {}", setup.path, e))
+            .ok()?;
+        log::info!("loaded script {} for player {}", setup.path, setup.player);
+
+        let mut scope = Scope::new();
+        scope.push_constant("PLAYER", setup.player as INT);
+        scope.push_constant(
+            "TEAM",
+            teams.team_of(setup.player).map(|t| t as INT).unwrap_or(-1),
+        );
+
+        Some(LoadedScript {
+            player: setup.player,
+            path: setup.path.clone(),
+            engine,
+            ast,
+            scope,
+            output,
+            relations,
+            failed: false,
+        })
+    }
+}
+
+/// runs each script, then applies whatever it asked for
+pub fn script_system(
+    game_speed: Res<GameSpeed>,
+    teams: Res<TeamsResource>,
+    mut battle: ResMut<BattleResource>,
+    mut scripts: ResMut<ScriptResource>,
+    mut unit_events: ResMut<Events<UnitInteractionEvent>>,
+    mut messages: ResMut<Events<ScenarioMessage>>,
+    units: Query<(
+        Entity,
+        &UnitComponent,
+        &Transform,
+        &HealthComponent,
+        Option<&MoraleComponent>,
+        Option<&AbilitiesComponent>,
+    )>,
+) {
+    if game_speed.is_paused() || battle.is_over() || scripts.scripts.is_empty() {
+        return;
+    }
+
+    let views: Array = units
+        .iter()
+        .map(|(entity, unit, transform, health, morale, abilities)| {
+            let ability_cmds = abilities
+                .map(available_ability_commands)
+                .unwrap_or_default();
+            Dynamic::from(UnitView {
+                id: entity.to_bits() as INT,
+                player: unit.player_id as INT,
+                team: teams
+                    .team_of(unit.player_id)
+                    .map(|t| t as INT)
+                    .unwrap_or(-1),
+                x: transform.translation.x as FLOAT,
+                y: transform.translation.y as FLOAT,
+                health: health.ratio() as FLOAT,
+                routing: morale.map(|m| m.is_routing()).unwrap_or(false),
+                state: UnitView::state_name(&unit.state).into(),
+                unit_type: format!("{:?}", unit.unit_type).into(),
+                abilities: ability_cmds
+                    .iter()
+                    .filter_map(|cmd| match cmd {
+                        UnitUiCommand::ActivateAbility(a) => {
+                            Some(Dynamic::from(ImmutableString::from(a.name())))
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+                ability_active: ability_cmds
+                    .iter()
+                    .any(|cmd| matches!(cmd, UnitUiCommand::DeactivateAbility)),
+            })
+        })
+        .collect();
+    let elapsed = battle.elapsed as FLOAT;
+
+    for script in scripts.scripts.iter_mut().filter(|s| !s.failed) {
+        // relations can change mid-battle, eg from a trigger
+        if let Ok(mut relations) = script.relations.lock() {
+            *relations = teams.clone();
+        }
+
+        let result: Result<(), _> = script.engine.call_fn(
+            &mut script.scope,
+            &script.ast,
+            "on_tick",
+            (views.clone(), elapsed),
+        );
+        if let Err(e) = result {
+            log::error!("script {} failed, disabling it: {}", script.path, e);
+            script.failed = true;
+        }
+
+        let mut output = match script.output.lock() {
+            Ok(output) => output,
+            Err(_) => continue,
+        };
+        for (entity, cmd) in output.commands.drain(..) {
+            unit_events.send(UnitInteractionEvent::Ui(script.player, entity, cmd));
+        }
+        for msg in output.messages.drain(..) {
+            messages.send(ScenarioMessage(msg));
+        }
+        if let Some(winner) = output.end_battle.take() {
+            battle.end_battle(winner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_script_api() {
+        let setup = ScriptSetup {
+            player: 2,
+            path: "test".to_string(),
+        };
+        let source = r#"
+            fn on_tick(units, time) {
+                for u in units {
+                    if u.player == PLAYER && u.health > 0.5 {
+                        run_to(u.id, 10.0, -u.y);
+                    }
+                    for other in units {
+                        if is_foe(u, other) {
+                            charge(u.id, other.id);
+                        }
+                    }
+                }
+                while true {}
+            }
+        "#;
+        let teams = TeamsResource::from_setup(&TeamSetup::free_for_all(&[1, 2]));
+        let mut script = ScriptResource::compile(&setup, source, &teams).expect("compiles");
+        assert_eq!(script.scope.get_value::<INT>("PLAYER"), Some(2));
+        assert_eq!(script.scope.get_value::<INT>("TEAM"), Some(1));
+
+        let unit = UnitView {
+            id: 7,
+            player: 2,
+            team: 1,
+            x: 0.0,
+            y: 5.0,
+            health: 1.0,
+            routing: false,
+            state: "idle".into(),
+            unit_type: "MeleeInfantry".into(),
+            abilities: vec![],
+            ability_active: false,
+        };
+        // an enemy, and a unit whose player isn't on any team
+        let enemy = UnitView {
+            id: 8,
+            player: 1,
+            team: 0,
+            ..unit.clone()
+        };
+        let teamless = UnitView {
+            id: 9,
+            player: 3,
+            team: -1,
+            ..unit.clone()
+        };
+        let units: Array = vec![
+            Dynamic::from(unit),
+            Dynamic::from(enemy),
+            Dynamic::from(teamless),
+        ];
+        let result: Result<(), _> = script.engine.call_fn(
+            &mut script.scope,
+            &script.ast,
+            "on_tick",
+            (units, 0.0 as FLOAT),
+        );
+
+        // the infinite loop is cut off, but commands issued before it still count
+        assert!(result.is_err());
+        let output = script.output.lock().unwrap();
+        assert_eq!(output.commands.len(), 3);
+        assert_eq!(output.commands[0].0, entity_from_id(7));
+        assert!(matches!(
+            output.commands[1],
+            (_, UnitUiCommand::Attack(target, _)) if target == entity_from_id(8)
+        ));
+        assert!(matches!(
+            output.commands[2],
+            (_, UnitUiCommand::Attack(target, _)) if target == entity_from_id(7)
+        ));
+    }
+}
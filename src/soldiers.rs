@@ -0,0 +1,276 @@
+//! Optional mode where a unit is made up of individual soldier entities.
+//!
+//! The unit entity keeps its `UnitComponent` and acts as the command interface and footprint
+//! for the state machine, while each soldier has its own body, collider and small health pool.
+//! The units `HealthComponent` then tracks the number of men still alive.
+
+use bevy::prelude::*;
+
+use bevy_rapier2d::physics::{ColliderHandleComponent, RigidBodyHandleComponent};
+use bevy_rapier2d::rapier::dynamics::RigidBodySet;
+use bevy_rapier2d::rapier::geometry::ColliderSet;
+use bevy_rapier2d::rapier::math::{Isometry, Vector};
+
+use crate::abilities::*;
+use crate::combat::*;
+use crate::formation::*;
+use crate::*;
+
+pub const SOLDIER_HP: f32 = 10.0;
+pub const SOLDIER_SIZE: f32 = 4.0;
+/// distance between the centres of neighbouring soldiers in a block
+pub const SOLDIER_SPACING: f32 = 6.0;
+
+/// attached to the unit entity when it is made up of individual soldiers
+#[derive(Debug, Default)]
+pub struct SoldiersComponent {
+    /// alive soldiers, in slot order. The first soldiers are in the front rank.
+    pub soldiers: Vec<Entity>,
+}
+
+pub struct SoldierComponent {
+    /// the unit this soldier belongs to
+    pub unit: Entity,
+    pub current_health: f32,
+    pub max_health: f32,
+    /// enemy soldiers (or single-body units) this soldier is touching
+    pub melee_range: Vec<Entity>,
+    /// non-hostile soldiers (or single-body units) of other units this soldier is touching,
+    /// kept in case they become enemies
+    pub friendly_contacts: Vec<Entity>,
+}
+
+impl SoldierComponent {
+    pub fn new(unit: Entity) -> Self {
+        SoldierComponent {
+            unit,
+            current_health: SOLDIER_HP,
+            max_health: SOLDIER_HP,
+            melee_range: Vec::new(),
+            friendly_contacts: Vec::new(),
+        }
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.current_health <= 0.0
+    }
+
+    /// re-sorts contacts into foes and friends after a change in team relations
+    pub fn reclassify(&mut self, is_hostile: &dyn Fn(Entity) -> bool) {
+        let (hostile, friendly): (Vec<Entity>, Vec<Entity>) = self
+            .melee_range
+            .iter()
+            .chain(self.friendly_contacts.iter())
+            .cloned()
+            .partition(|e| is_hostile(*e));
+        self.melee_range = hostile;
+        self.friendly_contacts = friendly;
+    }
+
+    /// forgets a soldier or unit that is gone
+    pub fn forget(&mut self, other: Entity) {
+        self.melee_range.retain(|e| *e != other);
+        self.friendly_contacts.retain(|e| *e != other);
+    }
+}
+
+impl SoldiersComponent {
+    /// the soldier that takes a hit from an attacker in melee. Always the front rank.
+    pub fn melee_casualty(&self) -> Option<Entity> {
+        self.soldiers.get(0).cloned()
+    }
+}
+
+/// keeps soldiers in their slots around the unit centre
+pub fn soldier_movement_system(
+    game_speed: Res<GameSpeed>,
+    mut bodies: ResMut<RigidBodySet>,
+    mut colliders: ResMut<ColliderSet>,
+    unit_query: Query<(
+        &SoldiersComponent,
+        &FormationComponent,
+        &Transform,
+        &FacingComponent,
+    )>,
+    soldier_query: Query<(&RigidBodyHandleComponent, &ColliderHandleComponent)>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    for (soldiers, formation, transform, facing) in unit_query.iter() {
+        let unit_pos: XyPos = (transform.translation.x, transform.translation.y).into();
+        let count = soldiers.soldiers.len();
+
+        for (slot, soldier) in soldiers.soldiers.iter().enumerate() {
+            if let Ok((body_handle, collider_handle)) = soldier_query.get(soldier.clone()) {
+                let dest = unit_pos + formation.slot_offset(slot, count, facing.0);
+                let pos = Isometry::new(Vector::new(dest.x, dest.y), facing_angle(facing.0));
+
+                bodies
+                    .get_mut(body_handle.handle())
+                    .expect("body")
+                    .set_position(pos, true);
+                colliders
+                    .get_mut(collider_handle.handle())
+                    .expect("collider")
+                    .set_position_debug(pos);
+            }
+        }
+    }
+}
+
+/// resolves melee between soldiers in contact. Soldiers only fight while their unit is in melee.
+pub fn soldier_melee_system(
+    game_speed: Res<GameSpeed>,
+    time: Res<Time>,
+    matchups: Res<MatchupTable>,
+    mut active_matchups: ResMut<ActiveMatchups>,
+    mut unit_events: ResMut<Events<UnitInteractionEvent>>,
+    unit_query: Query<(&UnitComponent, &CombatComponent, &FacingComponent)>,
+    mut soldier_query: Query<(Entity, &mut SoldierComponent)>,
+    mut timers: Query<&mut MeleeTimerComponent>,
+    mut health_query: Query<&mut HealthComponent>,
+    mut morale_query: Query<&mut MoraleComponent>,
+    transform_query: Query<&Transform>,
+    abilities: Query<&AbilitiesComponent>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    // collect hits first so we don't double-borrow the soldier components
+    let mut hits = vec![];
+
+    for (soldier_id, soldier) in soldier_query.iter() {
+        // the unit may have died this tick, before its soldiers are cleaned up
+        let (unit, source, _) = match unit_query.get(soldier.unit) {
+            Ok(unit) => unit,
+            Err(_) => continue,
+        };
+        let in_melee = matches!(unit.state, UnitState::Melee(_));
+
+        // first thing in contact that is still alive
+        let target = soldier.melee_range.iter().cloned().find(|e| {
+            soldier_query
+                .get_component::<SoldierComponent>(e.clone())
+                .map(|s| !s.is_dead())
+                .unwrap_or_else(|_| unit_query.get(e.clone()).is_ok())
+        });
+
+        let strikes = timers
+            .get_component_mut::<MeleeTimerComponent>(soldier_id)
+            .map(|mut timer| timer.tick(time.delta_seconds(), in_melee && target.is_some(), source))
+            .unwrap_or(0);
+
+        if let Some(target) = target.filter(|_| strikes > 0) {
+            let target_unit_id = soldier_query
+                .get_component::<SoldierComponent>(target)
+                .map(|s| s.unit)
+                .unwrap_or(target);
+            let (target_unit, target_combat, target_facing) = match unit_query.get(target_unit_id) {
+                Ok(target_unit) => target_unit,
+                Err(_) => continue,
+            };
+
+            let bonus = matchups.bonus(unit.unit_type, target_unit.unit_type);
+            // one entry per pair of units, however many of their soldiers are fighting
+            if !bonus.is_none()
+                && !active_matchups
+                    .0
+                    .iter()
+                    .any(|m| m.attacker == soldier.unit && m.defender == target_unit_id)
+            {
+                active_matchups.0.push(ActiveMatchup {
+                    attacker: soldier.unit,
+                    defender: target_unit_id,
+                    attacker_type: unit.unit_type,
+                    defender_type: target_unit.unit_type,
+                    bonus,
+                });
+            }
+
+            let source = effective_combat(&abilities, soldier.unit, source);
+            let target_combat = effective_combat(&abilities, target_unit_id, target_combat);
+
+            let (attacker_pos, target_pos) = match (
+                transform_query.get_component::<Transform>(soldier_id),
+                transform_query.get_component::<Transform>(target),
+            ) {
+                (Ok(attacker), Ok(target)) => (attacker.translation, target.translation),
+                _ => continue,
+            };
+            let direction = attack_direction(
+                (target_pos.x, target_pos.y).into(),
+                target_facing.0,
+                (attacker_pos.x, attacker_pos.y).into(),
+            );
+
+            for _ in 0..strikes {
+                if calc_melee_hit(&source, &target_combat, &bonus, direction) {
+                    hits.push((
+                        target,
+                        target_unit_id,
+                        calc_damage(&source, &target_combat, &bonus),
+                        direction,
+                    ));
+                }
+            }
+        }
+    }
+
+    for (target, target_unit_id, damage, direction) in hits {
+        if let Ok(mut target_soldier) = soldier_query.get_component_mut::<SoldierComponent>(target)
+        {
+            target_soldier.current_health -= damage;
+        } else if let Ok(mut target_health) =
+            health_query.get_component_mut::<HealthComponent>(target)
+        {
+            // fighting against a single-body unit
+            target_health.current_health -= damage;
+            if target_health.current_health < 0.0 {
+                log::info!("unit dead!");
+                unit_events.send(UnitInteractionEvent::UnitDied(target));
+            }
+        }
+        apply_direction_morale_penalty(&mut morale_query, target_unit_id, direction);
+    }
+}
+
+/// removes soldiers that have been killed, and updates the unit headcount.
+/// Any system can damage a soldier, this is where they get cleaned up.
+pub fn soldier_casualty_system(
+    mut commands: Commands,
+    mut unit_events: ResMut<Events<UnitInteractionEvent>>,
+    mut soldier_query: Query<(Entity, &mut SoldierComponent)>,
+    mut unit_query: Query<(&mut SoldiersComponent, &mut HealthComponent)>,
+) {
+    let dead: Vec<(Entity, Entity)> = soldier_query
+        .iter_mut()
+        .filter(|(_, s)| s.is_dead())
+        .map(|(e, s)| (e, s.unit))
+        .collect();
+
+    if dead.is_empty() {
+        return;
+    }
+
+    for (soldier, unit) in dead.iter() {
+        if let Ok((mut soldiers, mut health)) = unit_query.get_mut(unit.clone()) {
+            soldiers.soldiers.retain(|e| e != soldier);
+            health.current_health = soldiers.soldiers.len() as f32;
+
+            if soldiers.soldiers.is_empty() {
+                log::info!("unit dead!");
+                unit_events.send(UnitInteractionEvent::UnitDied(unit.clone()));
+            }
+        }
+        commands.despawn(soldier.clone());
+    }
+
+    for (_, mut soldier) in soldier_query.iter_mut() {
+        for (dead_soldier, _) in dead.iter() {
+            soldier.forget(*dead_soldier);
+        }
+    }
+}
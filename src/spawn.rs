@@ -0,0 +1,308 @@
+//! Spawning units, with everything they need to take part in the battle.
+//!
+//! Both the units a battle starts with and reinforcements that arrive later are spawned
+//! through `spawn_unit`, so they all get the same components, physics and UI.
+
+use std::fmt;
+
+use bevy::prelude::*;
+use bevy_rapier2d::rapier::dynamics::RigidBodyBuilder;
+use bevy_rapier2d::rapier::geometry::ColliderBuilder;
+
+use crate::abilities::*;
+use crate::combat::*;
+use crate::formation::*;
+use crate::line_of_sight::*;
+use crate::physics::*;
+use crate::pursuit::*;
+use crate::scenario::*;
+use crate::soldiers::*;
+use crate::ui;
+use crate::units::*;
+use crate::victory::BattleResource;
+use crate::*;
+
+pub const UNIT_SIZE: f32 = 30.0;
+pub const STATE_ICON_SIZE: f32 = 12.0;
+
+pub struct SpawnMaterials {
+    pub soldier: Handle<ColorMaterial>,
+}
+
+impl FromResources for SpawnMaterials {
+    fn from_resources(resources: &Resources) -> Self {
+        let mut materials = resources
+            .get_mut::<Assets<ColorMaterial>>()
+            .expect("Colour resource");
+        SpawnMaterials {
+            soldier: materials.add(Color::rgb(0.4, 0.4, 0.4).into()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpawnError {
+    /// the unit fights at range, but has no primary missile weapon to do it with
+    NoMissileWeapon(UnitType),
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnError::NoMissileWeapon(ut) => {
+                write!(f, "{:?} fights at range but has no missile weapon", ut)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
+/// spawns a unit, its soldiers if it has any, and its UI. Returns the unit entity, or an
+/// error if the unit can't be spawned, in which case nothing is spawned.
+pub fn spawn_unit(
+    commands: &mut Commands,
+    spawn: &UnitSpawn,
+    selection_materials: &ui::SelectionMaterials,
+    healthbar_materials: &ui::HeathBarMaterials,
+    spawn_materials: &SpawnMaterials,
+    e_to_ct: &mut EntityToColliderType,
+) -> Result<Entity, SpawnError> {
+    let (ut, player, soldier_count) = (spawn.unit_type, spawn.player, spawn.soldiers);
+    let (x, y) = (spawn.position.x, spawn.position.y);
+    let (unit, missile) = UnitComponent::default_from_type(ut, player);
+
+    let body = RigidBodyBuilder::new_dynamic()
+        .translation(x, y)
+        .can_sleep(false); // things start annoyingly asleep
+
+    // TODO add more colliders when bevy_rapier supports it.
+    // for now, missile units cant engage in melee
+    let (collider, collider_type) = if let AttackType::Melee = &unit.primary_attack_type() {
+        (
+            ColliderBuilder::cuboid(UNIT_SIZE / 2.0, UNIT_SIZE / 2.0).sensor(true),
+            ColliderType::Melee,
+        )
+    } else if let MissileWeaponComponent::Primary(stats) = &missile {
+        (
+            ColliderBuilder::ball(stats.range).sensor(true),
+            ColliderType::FiringRange,
+        )
+    } else {
+        return Err(SpawnError::NoMissileWeapon(ut));
+    };
+
+    // start off facing the centre of the battlefield
+    let mut facing = FacingComponent::default();
+    facing.face(-XyPos::new(x, y));
+    let facing_direction = facing.0;
+
+    let (health, formation) = if soldier_count > 0 {
+        (
+            HealthComponent::new(soldier_count as f32),
+            FormationComponent::square(soldier_count),
+        )
+    } else {
+        (
+            HealthComponent::default(),
+            FormationComponent::square(SINGLE_BODY_MEN),
+        )
+    };
+    let soldier_offsets: Vec<XyPos> = (0..soldier_count)
+        .map(|slot| formation.slot_offset(slot, soldier_count, facing_direction))
+        .collect();
+
+    commands
+        .spawn(SpriteComponents {
+            material: selection_materials.normal.clone_weak().into(),
+            transform: Transform::from_translation(Vec3::new(x, y, 1.0)),
+            sprite: Sprite::new(Vec2::new(UNIT_SIZE, UNIT_SIZE)),
+            ..Default::default()
+        })
+        .with(unit)
+        .with(missile)
+        .with(WaypointComponent::default())
+        .with(health)
+        .with(formation)
+        .with(AbilitiesComponent::default_from_type(ut))
+        .with(CombatComponent::default_from_type(ut))
+        .with(MeleeTimerComponent::default())
+        .with(MoraleComponent::default())
+        .with(facing)
+        .with(VelocityComponent::default())
+        .with(NearbyUnitsComponent::default())
+        .with(LineOfSightComponent::default())
+        .with(PursuitComponent::default())
+        .with_bundle((body, collider))
+        // ui state icon
+        .with_children(|parent| {
+            parent.spawn(SpriteComponents {
+                sprite: Sprite::new(Vec2::new(STATE_ICON_SIZE, STATE_ICON_SIZE)),
+                material: selection_materials.normal.clone_weak().into(),
+                global_transform: GlobalTransform::from_translation(Vec3::new(
+                    (UNIT_SIZE / 2.0) + (STATE_ICON_SIZE / 2.0) + 5.0,
+                    (UNIT_SIZE / 2.0) - (STATE_ICON_SIZE / 2.0),
+                    0.0,
+                )),
+                // .apply_non_uniform_scale(Vec3::new(ui::ICON_SCALE, ui::ICON_SCALE, ui::ICON_SCALE)),
+                ..Default::default()
+            });
+        })
+        // healthbar
+        .with_children(|parent| {
+            let xpos = 0.0;
+            let ypos = -(UNIT_SIZE / 2.0) - 5.0;
+
+            // background
+            parent.spawn(SpriteComponents {
+                material: healthbar_materials.background.clone_weak().into(),
+                transform: Transform::from_translation(Vec3::new(xpos, ypos, 1.0)),
+                sprite: Sprite::new(Vec2::new(UNIT_SIZE, 5.0)),
+                ..Default::default()
+            });
+            // foreground
+            parent.spawn(SpriteComponents {
+                material: healthbar_materials.high.clone_weak().into(),
+                transform: Transform::from_translation(Vec3::new(xpos, ypos, 2.0)),
+                sprite: Sprite::new(Vec2::new(UNIT_SIZE, 5.0)),
+                ..Default::default()
+            });
+        });
+
+    let unit_e = commands.current_entity().expect("Unit entity");
+    e_to_ct.0.insert(unit_e, collider_type);
+
+    if let Some(name) = &spawn.name {
+        commands.insert_one(unit_e, UnitNameComponent(name.clone()));
+    }
+
+    // optionally make the unit out of individual soldiers
+    if soldier_count > 0 {
+        let mut soldiers = SoldiersComponent::default();
+
+        for offset in soldier_offsets.into_iter() {
+            let (sx, sy) = (x + offset.x, y + offset.y);
+
+            let body = RigidBodyBuilder::new_dynamic()
+                .translation(sx, sy)
+                .can_sleep(false);
+            let collider =
+                ColliderBuilder::cuboid(SOLDIER_SIZE / 2.0, SOLDIER_SIZE / 2.0).sensor(true);
+
+            let soldier_e = commands
+                .spawn(SpriteComponents {
+                    material: spawn_materials.soldier.clone(),
+                    transform: Transform::from_translation(Vec3::new(sx, sy, 3.0)),
+                    sprite: Sprite::new(Vec2::new(SOLDIER_SIZE, SOLDIER_SIZE)),
+                    ..Default::default()
+                })
+                .with(SoldierComponent::new(unit_e))
+                .with(MeleeTimerComponent::default())
+                .with_bundle((body, collider))
+                .current_entity()
+                .expect("Soldier entity");
+
+            e_to_ct.0.insert(soldier_e, ColliderType::Soldier);
+            soldiers.soldiers.push(soldier_e);
+        }
+
+        commands.insert_one(unit_e, soldiers);
+    }
+
+    Ok(unit_e)
+}
+
+/// send to bring in the scenario's `OnCall` reinforcement group with this index
+#[derive(Clone, Copy, Debug)]
+pub struct CallReinforcements(pub usize);
+
+#[derive(Default)]
+pub struct ReinforcementState {
+    calls: EventReader<CallReinforcements>,
+    /// indices of the reinforcement groups that have already arrived
+    arrived: Vec<usize>,
+}
+
+impl ReinforcementState {
+    /// the groups that arrive now, given the battle time and which `OnCall` groups were called.
+    /// Each group only arrives once.
+    pub fn arriving(
+        &mut self,
+        reinforcements: &[Reinforcement],
+        elapsed: f32,
+        called: &[usize],
+    ) -> Vec<usize> {
+        let mut arriving = vec![];
+        for (idx, reinforcement) in reinforcements.iter().enumerate() {
+            if self.arrived.contains(&idx) {
+                continue;
+            }
+            let due = match reinforcement.arrival {
+                ReinforcementArrival::AtTime(time) => elapsed >= time,
+                ReinforcementArrival::OnCall => called.contains(&idx),
+            };
+            if due {
+                self.arrived.push(idx);
+                arriving.push(idx);
+            }
+        }
+        arriving
+    }
+}
+
+/// spawns reinforcements when they are due
+pub fn reinforcement_system(
+    mut commands: Commands,
+    mut state: Local<ReinforcementState>,
+    scenario: Res<Scenario>,
+    battle: Res<BattleResource>,
+    calls: Res<Events<CallReinforcements>>,
+    selection_materials: Res<ui::SelectionMaterials>,
+    healthbar_materials: Res<ui::HeathBarMaterials>,
+    spawn_materials: Res<SpawnMaterials>,
+    mut e_to_ct: ResMut<EntityToColliderType>,
+) {
+    let state = &mut *state;
+    let called: Vec<usize> = state.calls.iter(&calls).map(|c| c.0).collect();
+
+    for idx in state.arriving(&scenario.reinforcements, battle.elapsed, &called) {
+        log::info!("reinforcement group {} has arrived", idx);
+        for spawn in scenario.reinforcements[idx].units.iter() {
+            if let Err(e) = spawn_unit(
+                &mut commands,
+                spawn,
+                &selection_materials,
+                &healthbar_materials,
+                &spawn_materials,
+                &mut e_to_ct,
+            ) {
+                log::error!("skipping reinforcement {:?}: {}", spawn, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reinforcement_arrival() {
+        let group = |arrival| Reinforcement {
+            arrival,
+            units: vec![],
+        };
+        let reinforcements = vec![
+            group(ReinforcementArrival::AtTime(60.0)),
+            group(ReinforcementArrival::OnCall),
+        ];
+        let mut state = ReinforcementState::default();
+
+        assert!(state.arriving(&reinforcements, 30.0, &[]).is_empty());
+        assert_eq!(state.arriving(&reinforcements, 60.0, &[]), vec![0]);
+        assert_eq!(state.arriving(&reinforcements, 61.0, &[1]), vec![1]);
+
+        // nobody arrives twice
+        assert!(state.arriving(&reinforcements, 90.0, &[1]).is_empty());
+    }
+}
@@ -0,0 +1,292 @@
+//! How units choose what to shoot at, and who to fight.
+//!
+//! Each unit carries a `TargetingPolicy`, which ranks the targets within its missile range.
+//! Melee targets are ranked by threat, and spread out between friendly units so they don't
+//! all pile onto the same enemy. The state machine then picks the best ranked target.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::combat::{attack_direction, AttackDirection};
+use crate::teams::TeamsResource;
+use crate::units::NearbyUnitsComponent;
+use crate::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TargetingPolicy {
+    Nearest,
+    /// lowest remaining health
+    Weakest,
+    /// missile units and cavalry first
+    MostDangerous,
+    LowestArmour,
+    /// nearest target that isn't fighting friendlies in melee, so we don't shoot our own
+    AvoidFriendlyMelee,
+}
+
+/// what a unit knows about a potential target when ranking it
+#[derive(Clone, Copy, Debug)]
+pub struct TargetInfo {
+    pub entity: Entity,
+    pub distance: f32,
+    pub health: f32,
+    pub armour: f32,
+    pub unit_type: UnitType,
+    /// in melee with one of our units
+    pub engaged_with_friendlies: bool,
+}
+
+/// what a unit knows about an enemy it is in contact with
+#[derive(Clone, Copy, Debug)]
+pub struct MeleeTargetInfo {
+    pub entity: Entity,
+    /// the player ordered us to attack this unit
+    pub commanded: bool,
+    /// in contact with our flank or rear
+    pub flanking: bool,
+    /// number of friendly units already fighting it
+    pub engaged_by: usize,
+    pub health: f32,
+}
+
+impl Default for TargetingPolicy {
+    fn default() -> Self {
+        TargetingPolicy::Nearest
+    }
+}
+
+impl TargetingPolicy {
+    /// the next policy, for cycling through them from the UI
+    pub fn next(&self) -> Self {
+        match self {
+            TargetingPolicy::Nearest => TargetingPolicy::Weakest,
+            TargetingPolicy::Weakest => TargetingPolicy::MostDangerous,
+            TargetingPolicy::MostDangerous => TargetingPolicy::LowestArmour,
+            TargetingPolicy::LowestArmour => TargetingPolicy::AvoidFriendlyMelee,
+            TargetingPolicy::AvoidFriendlyMelee => TargetingPolicy::Nearest,
+        }
+    }
+
+    /// orders targets from most to least preferred. Targets that should never be shot at are
+    /// removed. Ties are broken by distance.
+    pub fn rank(&self, mut targets: Vec<TargetInfo>) -> Vec<Entity> {
+        if let TargetingPolicy::AvoidFriendlyMelee = self {
+            targets.retain(|t| !t.engaged_with_friendlies);
+        }
+
+        let key = |t: &TargetInfo| -> f32 {
+            match self {
+                TargetingPolicy::Nearest | TargetingPolicy::AvoidFriendlyMelee => 0.0,
+                TargetingPolicy::Weakest => t.health,
+                TargetingPolicy::MostDangerous => {
+                    if t.unit_type.is_missile() || t.unit_type.is_cavalry() {
+                        0.0
+                    } else {
+                        1.0
+                    }
+                }
+                TargetingPolicy::LowestArmour => t.armour,
+            }
+        };
+
+        targets.sort_by(|a, b| {
+            key(a).partial_cmp(&key(b)).unwrap_or(Ordering::Equal).then(
+                a.distance
+                    .partial_cmp(&b.distance)
+                    .unwrap_or(Ordering::Equal),
+            )
+        });
+        targets.into_iter().map(|t| t.entity).collect()
+    }
+}
+
+/// orders melee targets from most to least preferred: the commanded target, then anything
+/// hitting our flank or rear, then whoever has the fewest friendlies on them, then the weakest
+pub fn rank_melee_targets(mut targets: Vec<MeleeTargetInfo>) -> Vec<Entity> {
+    targets.sort_by(|a, b| {
+        b.commanded
+            .cmp(&a.commanded)
+            .then(b.flanking.cmp(&a.flanking))
+            .then(a.engaged_by.cmp(&b.engaged_by))
+            .then(a.health.partial_cmp(&b.health).unwrap_or(Ordering::Equal))
+    });
+    targets.into_iter().map(|t| t.entity).collect()
+}
+
+/// ranks the units in each units melee range. Units are assigned one at a time, so later
+/// units can see who earlier units are going for and spread out across the enemy.
+pub fn melee_targeting_system(
+    game_speed: Res<GameSpeed>,
+    mut units: Query<(
+        Entity,
+        &UnitComponent,
+        &Transform,
+        &FacingComponent,
+        &mut NearbyUnitsComponent,
+    )>,
+    targets: Query<(&HealthComponent, &Transform)>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    let mut engaged_by: HashMap<Entity, usize> = HashMap::new();
+
+    for (entity, unit, transform, facing, mut nearbys) in units.iter_mut() {
+        let pos: XyPos = (transform.translation.x, transform.translation.y).into();
+
+        let infos = nearbys
+            .melee_range()
+            .iter()
+            .filter(|e| **e != entity)
+            .filter_map(|e| targets.get(e.clone()).ok().map(|t| (e.clone(), t)))
+            .map(|(target, (health, target_transform))| {
+                let target_pos: XyPos = (
+                    target_transform.translation.x,
+                    target_transform.translation.y,
+                )
+                    .into();
+
+                MeleeTargetInfo {
+                    entity: target,
+                    commanded: matches!(
+                        unit.current_command,
+                        UnitUserCommand::AttackMelee(cmd_target) if cmd_target == target
+                    ),
+                    flanking: attack_direction(pos, facing.0, target_pos) != AttackDirection::Front,
+                    engaged_by: engaged_by.get(&target).cloned().unwrap_or(0),
+                    health: health.current_health,
+                }
+            })
+            .collect();
+
+        let ranked = rank_melee_targets(infos);
+        if let Some(first) = ranked.first() {
+            *engaged_by.entry(first.clone()).or_insert(0) += 1;
+        }
+        nearbys.set_melee_targets(ranked);
+    }
+}
+
+/// ranks the units in each units missile range by its targeting policy
+pub fn missile_targeting_system(
+    game_speed: Res<GameSpeed>,
+    teams: Res<TeamsResource>,
+    mut units: Query<(
+        &UnitComponent,
+        &MissileWeaponComponent,
+        &Transform,
+        &mut NearbyUnitsComponent,
+    )>,
+    targets: Query<(
+        Entity,
+        &UnitComponent,
+        &HealthComponent,
+        &CombatComponent,
+        &Transform,
+    )>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    for (unit, missile, transform, mut nearbys) in units.iter_mut() {
+        if missile.stats().is_none() {
+            continue;
+        }
+        let pos: XyPos = (transform.translation.x, transform.translation.y).into();
+
+        let is_friendly = |e: Entity| {
+            targets
+                .get_component::<UnitComponent>(e)
+                .map(|u| !teams.is_foe(u.player_id, unit.player_id))
+                .unwrap_or(false)
+        };
+
+        let infos = nearbys
+            .missile_range()
+            .iter()
+            .filter_map(|e| targets.get(e.clone()).ok())
+            .map(|(entity, target, health, combat, target_transform)| {
+                let target_pos: XyPos = (
+                    target_transform.translation.x,
+                    target_transform.translation.y,
+                )
+                    .into();
+
+                // either side of the fight could be ours
+                let engaged_with_friendlies = target
+                    .state
+                    .current_actively_fighting()
+                    .map(|e| is_friendly(e))
+                    .unwrap_or(false)
+                    || targets.iter().any(|(_, other, _, _, _)| {
+                        !teams.is_foe(other.player_id, unit.player_id)
+                            && other.state.current_actively_fighting() == Some(entity)
+                    });
+
+                TargetInfo {
+                    entity,
+                    distance: (target_pos - pos).length(),
+                    health: health.current_health,
+                    armour: combat.armour,
+                    unit_type: target.unit_type,
+                    engaged_with_friendlies,
+                }
+            })
+            .collect();
+
+        nearbys.set_missile_targets(unit.targeting_policy.rank(infos));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_targeting_policies() {
+        let target =
+            |id: u32, distance: f32, health: f32, unit_type: UnitType, engaged: bool| TargetInfo {
+                entity: Entity::new(id),
+                distance,
+                health,
+                armour: 50.0,
+                unit_type,
+                engaged_with_friendlies: engaged,
+            };
+        let targets = vec![
+            target(0, 10.0, 100.0, UnitType::MeleeInfantry, true),
+            target(1, 50.0, 20.0, UnitType::SpearInfantry, false),
+            target(2, 80.0, 100.0, UnitType::MissileInfantry, false),
+        ];
+
+        let first = |policy: TargetingPolicy| policy.rank(targets.clone())[0];
+        assert_eq!(first(TargetingPolicy::Nearest), Entity::new(0));
+        assert_eq!(first(TargetingPolicy::Weakest), Entity::new(1));
+        assert_eq!(first(TargetingPolicy::MostDangerous), Entity::new(2));
+        assert_eq!(first(TargetingPolicy::AvoidFriendlyMelee), Entity::new(1));
+    }
+
+    #[test]
+    fn test_melee_target_ranking() {
+        let target = |id: u32, flanking: bool, engaged_by: usize, health: f32| MeleeTargetInfo {
+            entity: Entity::new(id),
+            commanded: false,
+            flanking,
+            engaged_by,
+            health,
+        };
+
+        // spread out onto the enemy nobody is fighting yet
+        let ranked = rank_melee_targets(vec![target(0, false, 1, 50.0), target(1, false, 0, 80.0)]);
+        assert_eq!(ranked[0], Entity::new(1));
+
+        // but deal with flanking threats first
+        let ranked = rank_melee_targets(vec![target(0, false, 0, 50.0), target(1, true, 2, 80.0)]);
+        assert_eq!(ranked[0], Entity::new(1));
+    }
+}
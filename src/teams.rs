@@ -1,21 +1,49 @@
-//! resources for identifying what plays belong to which team
-//! TODO separate team/player ambiguity
+//! resources for identifying what players belong to which team
+//!
+//! Players own units, teams are groups of players that fight together, and teams can be
+//! allied with other teams. Every pair of teams has a relation, in both directions.
 
 use std::collections::HashMap;
+use std::fmt;
 
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 
 pub type TeamId = usize;
 pub type PlayerId = usize;
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum TeamRelation {
     Same,
     Allied,
     Enemy,
 }
 
-#[derive(Default, Debug)]
+/// the player controlling this instance of the game. Only their units can be selected and
+/// commanded from the mouse and keyboard.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LocalPlayer(pub PlayerId);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TeamError {
+    /// the player was never added to a team
+    UnknownPlayer(PlayerId),
+    /// there is no relation set up between these two teams
+    MissingRelation(TeamId, TeamId),
+    /// the relation between two teams is different depending on which way round you ask
+    AsymmetricRelation(TeamId, TeamId),
+}
+
+/// which players are on which teams, and which teams are allied. Usually comes from a scenario.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TeamSetup {
+    /// the players on each team, indexed by TeamId
+    pub teams: Vec<Vec<PlayerId>>,
+    /// pairs of teams that are allied. Every other pair of teams are enemies.
+    pub alliances: Vec<(TeamId, TeamId)>,
+}
+
+#[derive(Clone, Default, Debug)]
 pub struct TeamsResource {
     /// a lookup from (team, team): relation
     pub team_relationship_lookup: HashMap<(TeamId, TeamId), TeamRelation>,
@@ -24,34 +52,199 @@ pub struct TeamsResource {
 }
 
 
+impl fmt::Display for TeamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TeamError::UnknownPlayer(p) => write!(f, "player {} is not on any team", p),
+            TeamError::MissingRelation(t1, t2) => {
+                write!(f, "no relation between teams {} and {}", t1, t2)
+            }
+            TeamError::AsymmetricRelation(t1, t2) => {
+                write!(f, "teams {} and {} disagree on their relation", t1, t2)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TeamError {}
+
 impl TeamsResource {
+    /// false if the relation is unknown, so units never fight someone they aren't sure about.
+    /// This is called for every pair of units every tick, so it doesn't log; a bad team setup
+    /// is reported once by `validate` when the battle starts.
     pub fn is_foe(&self, p1: PlayerId, p2: PlayerId) -> bool {
-        self.get_relation(p1, p2) == TeamRelation::Enemy
+        self.get_relation(p1, p2).ok() == Some(TeamRelation::Enemy)
     }
-    
+
+    /// false if the relation is unknown, except that a player is always on their own side
     pub fn is_own(&self, p1: PlayerId, p2: PlayerId) -> bool {
-        self.get_relation(p1, p2) == TeamRelation::Same
+        p1 == p2 || self.get_relation(p1, p2).ok() == Some(TeamRelation::Same)
     }
-    
-    fn get_relation(&self, p1: PlayerId, p2: PlayerId) -> TeamRelation {
-        let t1 = self.player_team_lookup.get(&p1).expect("Invalid player").clone();
-        let t2 = self.player_team_lookup.get(&p2).expect("Invalid player").clone();
-        self.team_relationship_lookup.get(&(t1, t2)).expect("Invalid teams").clone()
+
+    /// true for a team and itself, or two allied teams. False if the relation is unknown.
+    pub fn teams_allied(&self, t1: TeamId, t2: TeamId) -> bool {
+        t1 == t2
+            || matches!(
+                self.team_relationship_lookup.get(&(t1, t2)),
+                Some(TeamRelation::Same) | Some(TeamRelation::Allied)
+            )
+    }
+
+    pub fn get_relation(&self, p1: PlayerId, p2: PlayerId) -> Result<TeamRelation, TeamError> {
+        let t1 = self.team_of(p1)?;
+        let t2 = self.team_of(p2)?;
+        if t1 == t2 {
+            return Ok(TeamRelation::Same);
+        }
+        self.team_relationship_lookup
+            .get(&(t1, t2))
+            .cloned()
+            .ok_or(TeamError::MissingRelation(t1, t2))
+    }
+
+    pub fn team_of(&self, p: PlayerId) -> Result<TeamId, TeamError> {
+        self.player_team_lookup
+            .get(&p)
+            .cloned()
+            .ok_or(TeamError::UnknownPlayer(p))
+    }
+
+    /// checks that every player in `players` is on a team, and that the relation table is
+    /// complete and symmetric. Should be called at the start of a battle.
+    pub fn validate(&self, players: &[PlayerId]) -> Result<(), TeamError> {
+        for p in players.iter() {
+            self.team_of(*p)?;
+        }
+
+        let teams = self.teams();
+        for t1 in teams.iter() {
+            for t2 in teams.iter() {
+                let rel = self
+                    .team_relationship_lookup
+                    .get(&(*t1, *t2))
+                    .ok_or(TeamError::MissingRelation(*t1, *t2))?;
+                if self.team_relationship_lookup.get(&(*t2, *t1)) != Some(rel) {
+                    return Err(TeamError::AsymmetricRelation(*t1, *t2));
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn add_player(&mut self, p: PlayerId, t: TeamId) {
         self.player_team_lookup.entry(p).or_insert(t);
     }
 
-    // TODO make foolproof and better
+    /// sets the relation between two teams, in both directions
+    pub fn set_relation(&mut self, t1: TeamId, t2: TeamId, rel: TeamRelation) {
+        self.team_relationship_lookup.insert((t1, t2), rel);
+        self.team_relationship_lookup.insert((t2, t1), rel);
+    }
+
+    /// splits `teams` into sides of teams that are all allied with each other, in order
+    pub fn sides(&self, teams: &[TeamId]) -> Vec<Vec<TeamId>> {
+        let mut sides: Vec<Vec<TeamId>> = vec![];
+        for team in teams.iter().cloned().unique().sorted() {
+            match sides
+                .iter_mut()
+                .find(|side| side.iter().all(|t| self.teams_allied(*t, team)))
+            {
+                Some(side) => side.push(team),
+                None => sides.push(vec![team]),
+            }
+        }
+        sides
+    }
+
+    /// all teams that have at least one player, in order
+    pub fn teams(&self) -> Vec<TeamId> {
+        self.player_team_lookup.values().cloned().unique().sorted().collect()
+    }
+
+    /// every team is the enemy of every other team
     pub fn free_for_all(&mut self) {
-        for (t1, t2) in self.player_team_lookup.keys().cloned().tuple_combinations::<(TeamId, TeamId)>() {
-            let rel = if t1 == t2 {
-                TeamRelation::Same
+        self.fill_relations(&[]);
+    }
+
+    pub fn from_setup(setup: &TeamSetup) -> Self {
+        let mut teams = TeamsResource::default();
+        for (team, players) in setup.teams.iter().enumerate() {
+            for player in players.iter() {
+                teams.add_player(*player, team);
+            }
+        }
+        teams.fill_relations(&setup.alliances);
+        teams
+    }
+
+    /// fills in the relation between every pair of teams, so the table is symmetric and complete
+    fn fill_relations(&mut self, alliances: &[(TeamId, TeamId)]) {
+        let teams = self.teams();
+        for t in teams.iter() {
+            self.set_relation(*t, *t, TeamRelation::Same);
+        }
+        for (t1, t2) in teams.into_iter().tuple_combinations::<(TeamId, TeamId)>() {
+            let rel = if alliances.contains(&(t1, t2)) || alliances.contains(&(t2, t1)) {
+                TeamRelation::Allied
             } else {
                 TeamRelation::Enemy
             };
-            self.team_relationship_lookup.insert((t1, t2), rel);
+            self.set_relation(t1, t2, rel);
+        }
+    }
+}
+
+impl TeamSetup {
+    /// one player per team, everyone against everyone, eg 1v1 or 1v1v1
+    pub fn free_for_all(players: &[PlayerId]) -> Self {
+        TeamSetup {
+            teams: players.iter().map(|p| vec![*p]).collect(),
+            alliances: vec![],
         }
     }
+
+    /// two teams of two players, eg 2v2
+    pub fn two_v_two(team_a: [PlayerId; 2], team_b: [PlayerId; 2]) -> Self {
+        TeamSetup {
+            teams: vec![team_a.to_vec(), team_b.to_vec()],
+            alliances: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_team_relations() {
+        let teams = TeamsResource::from_setup(&TeamSetup::two_v_two([1, 2], [3, 4]));
+        assert!(teams.is_own(1, 2));
+        assert!(teams.is_foe(1, 3));
+        assert!(teams.is_foe(4, 2));
+
+        let mut setup = TeamSetup::free_for_all(&[1, 2, 3]);
+        setup.alliances.push((0, 1));
+        let teams = TeamsResource::from_setup(&setup);
+        assert!(!teams.is_foe(2, 1));
+        assert!(!teams.is_own(1, 2));
+        assert!(teams.is_foe(3, 1));
+        assert!(teams.is_own(3, 3));
+        assert_eq!(teams.sides(&[2, 0, 1, 0]), vec![vec![0, 1], vec![2]]);
+    }
+
+    #[test]
+    fn test_unknown_players_dont_panic() {
+        let mut teams = TeamsResource::from_setup(&TeamSetup::free_for_all(&[1, 2]));
+        assert_eq!(teams.get_relation(1, 5), Err(TeamError::UnknownPlayer(5)));
+        assert!(!teams.is_foe(1, 5));
+        assert!(teams.validate(&[1, 2]).is_ok());
+        assert_eq!(teams.validate(&[1, 5]), Err(TeamError::UnknownPlayer(5)));
+
+        teams.team_relationship_lookup.remove(&(0, 1));
+        assert_eq!(
+            teams.validate(&[1, 2]),
+            Err(TeamError::MissingRelation(0, 1))
+        );
+    }
 }
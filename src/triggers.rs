@@ -0,0 +1,384 @@
+//! Scripted scenario events, for tutorials and challenge scenarios.
+//!
+//! A scenario declares a list of triggers, each a condition and the actions to run when it is
+//! first met. Each trigger fires at most once. Unit deaths are picked up from
+//! `UnitInteractionEvent`s, and commands are issued through them on behalf of the units owner.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::physics::EntityToColliderType;
+use crate::scenario::*;
+use crate::spawn::*;
+use crate::teams::*;
+use crate::ui;
+use crate::victory::BattleResource;
+use crate::*;
+
+/// which units a condition or action applies to
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum UnitSelector {
+    Any,
+    Player(PlayerId),
+    /// the unit given this name in the scenario
+    Named(String),
+}
+
+/// an axis-aligned box on the map
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Area {
+    pub min: XyPos,
+    pub max: XyPos,
+}
+
+/// conditions that involve units are met when any of the selected units meets them
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TriggerCondition {
+    /// seconds of battle time
+    TimeElapsed(f32),
+    UnitDied(UnitSelector),
+    UnitInArea(UnitSelector, Area),
+    /// health ratio, from 0 to 1
+    HealthBelow(UnitSelector, f32),
+    Routed(UnitSelector),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum TriggerAction {
+    SpawnUnits(Vec<UnitSpawn>),
+    /// issued as if by the owner of each unit
+    IssueCommand(UnitSelector, UnitUiCommand),
+    /// units already in contact are re-sorted into foes and friends by
+    /// `units::reclassify_contacts_system`
+    SetRelation(TeamId, TeamId, TeamRelation),
+    ShowMessage(String),
+    /// ends the battle, with the winning team or a draw
+    EndBattle(Option<TeamId>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Trigger {
+    pub condition: TriggerCondition,
+    pub actions: Vec<TriggerAction>,
+}
+
+/// a message for the player, eg tutorial instructions
+#[derive(Clone, Debug)]
+pub struct ScenarioMessage(pub String);
+
+/// what a trigger can see of a unit. Kept for dead units too, so deaths can be matched.
+#[derive(Clone, Debug)]
+pub struct UnitSnapshot {
+    pub player: PlayerId,
+    pub name: Option<String>,
+    pub position: XyPos,
+    pub health: f32,
+    pub routing: bool,
+}
+
+/// what the actions of the triggers that fired this tick ask for, applied by `trigger_system`
+#[derive(Debug, Default)]
+pub struct TriggerEffects {
+    pub spawns: Vec<UnitSpawn>,
+    /// each command, with the player issuing it
+    pub commands: Vec<(PlayerId, Entity, UnitUiCommand)>,
+    pub relations: Vec<(TeamId, TeamId, TeamRelation)>,
+    pub messages: Vec<String>,
+    /// the result, if a trigger ended the battle
+    pub end_battle: Option<Option<TeamId>>,
+}
+
+#[derive(Default)]
+pub struct TriggerState {
+    unit_events: EventReader<UnitInteractionEvent>,
+    /// indices of triggers that have already fired
+    fired: Vec<usize>,
+    /// the last known state of every unit, so we know who died
+    known: HashMap<Entity, UnitSnapshot>,
+}
+
+impl Area {
+    pub fn new(min: XyPos, max: XyPos) -> Self {
+        Area { min, max }
+    }
+
+    pub fn contains(&self, pos: XyPos) -> bool {
+        pos.x >= self.min.x && pos.x <= self.max.x && pos.y >= self.min.y && pos.y <= self.max.y
+    }
+}
+
+impl UnitSelector {
+    pub fn matches(&self, player: PlayerId, name: Option<&String>) -> bool {
+        match self {
+            UnitSelector::Any => true,
+            UnitSelector::Player(p) => *p == player,
+            UnitSelector::Named(n) => name == Some(n),
+        }
+    }
+
+    fn matches_unit(&self, unit: &UnitSnapshot) -> bool {
+        self.matches(unit.player, unit.name.as_ref())
+    }
+}
+
+impl TriggerCondition {
+    pub fn is_met(&self, elapsed: f32, alive: &[UnitSnapshot], died: &[UnitSnapshot]) -> bool {
+        let any_alive = |selector: &UnitSelector, f: &dyn Fn(&UnitSnapshot) -> bool| {
+            alive.iter().any(|u| selector.matches_unit(u) && f(u))
+        };
+
+        match self {
+            TriggerCondition::TimeElapsed(time) => elapsed >= *time,
+            TriggerCondition::UnitDied(selector) => died.iter().any(|u| selector.matches_unit(u)),
+            TriggerCondition::UnitInArea(selector, area) => {
+                any_alive(selector, &|u| area.contains(u.position))
+            }
+            TriggerCondition::HealthBelow(selector, ratio) => {
+                any_alive(selector, &|u| u.health < *ratio)
+            }
+            TriggerCondition::Routed(selector) => any_alive(selector, &|u| u.routing),
+        }
+    }
+}
+
+impl TriggerEffects {
+    fn add(&mut self, action: &TriggerAction, alive: &[(Entity, UnitSnapshot)]) {
+        match action {
+            TriggerAction::SpawnUnits(spawns) => self.spawns.extend(spawns.iter().cloned()),
+            TriggerAction::IssueCommand(selector, cmd) => {
+                for (entity, unit) in alive.iter().filter(|(_, u)| selector.matches_unit(u)) {
+                    self.commands.push((unit.player, *entity, *cmd));
+                }
+            }
+            TriggerAction::SetRelation(t1, t2, rel) => self.relations.push((*t1, *t2, *rel)),
+            TriggerAction::ShowMessage(msg) => self.messages.push(msg.clone()),
+            TriggerAction::EndBattle(winner) => self.end_battle = Some(*winner),
+        }
+    }
+}
+
+impl TriggerState {
+    /// records the latest state of the living units, and returns the last known state of those
+    /// in `died`. Units that are no longer alive are forgotten afterwards.
+    fn record(&mut self, alive: &[(Entity, UnitSnapshot)], died: &[Entity]) -> Vec<UnitSnapshot> {
+        for (entity, snapshot) in alive.iter() {
+            self.known.insert(*entity, snapshot.clone());
+        }
+        let died = died
+            .iter()
+            .filter_map(|e| self.known.get(e).cloned())
+            .collect();
+        self.known
+            .retain(|e, _| alive.iter().any(|(alive, _)| alive == e));
+        died
+    }
+
+    /// runs the actions of every trigger whose condition is met for the first time
+    fn fire(
+        &mut self,
+        triggers: &[Trigger],
+        elapsed: f32,
+        alive: &[(Entity, UnitSnapshot)],
+        died: &[UnitSnapshot],
+    ) -> TriggerEffects {
+        let snapshots: Vec<UnitSnapshot> = alive.iter().map(|(_, u)| u.clone()).collect();
+        let mut effects = TriggerEffects::default();
+        for (idx, trigger) in triggers.iter().enumerate() {
+            if self.fired.contains(&idx) || !trigger.condition.is_met(elapsed, &snapshots, died) {
+                continue;
+            }
+
+            log::info!("trigger {} fired: {:?}", idx, trigger.condition);
+            self.fired.push(idx);
+            for action in trigger.actions.iter() {
+                effects.add(action, alive);
+            }
+        }
+        effects
+    }
+}
+
+/// checks each triggers condition, and runs its actions the first time it is met
+pub fn trigger_system(
+    mut commands: Commands,
+    mut state: Local<TriggerState>,
+    game_speed: Res<GameSpeed>,
+    scenario: Res<Scenario>,
+    mut battle: ResMut<BattleResource>,
+    mut teams: ResMut<TeamsResource>,
+    mut unit_events: ResMut<Events<UnitInteractionEvent>>,
+    mut messages: ResMut<Events<ScenarioMessage>>,
+    selection_materials: Res<ui::SelectionMaterials>,
+    healthbar_materials: Res<ui::HeathBarMaterials>,
+    spawn_materials: Res<SpawnMaterials>,
+    mut e_to_ct: ResMut<EntityToColliderType>,
+    units: Query<(
+        Entity,
+        &UnitComponent,
+        &Transform,
+        &HealthComponent,
+        Option<&MoraleComponent>,
+        Option<&UnitNameComponent>,
+    )>,
+) {
+    if game_speed.is_paused() || battle.is_over() {
+        return;
+    }
+    let state = &mut *state;
+
+    let mut alive = vec![];
+    for (entity, unit, transform, health, morale, name) in units.iter() {
+        let snapshot = UnitSnapshot {
+            player: unit.player_id,
+            name: name.map(|n| n.0.clone()),
+            position: (transform.translation.x, transform.translation.y).into(),
+            health: health.ratio(),
+            routing: morale.map(|m| m.is_routing()).unwrap_or(false),
+        };
+        alive.push((entity, snapshot));
+    }
+
+    let died: Vec<Entity> = state
+        .unit_events
+        .iter(&unit_events)
+        .filter_map(|event| match event {
+            UnitInteractionEvent::UnitDied(e) => Some(*e),
+            _ => None,
+        })
+        .collect();
+    let died = state.record(&alive, &died);
+
+    let effects = state.fire(&scenario.triggers, battle.elapsed, &alive, &died);
+    for spawn in effects.spawns.iter() {
+        if let Err(e) = spawn_unit(
+            &mut commands,
+            spawn,
+            &selection_materials,
+            &healthbar_materials,
+            &spawn_materials,
+            &mut e_to_ct,
+        ) {
+            log::error!("skipping triggered spawn {:?}: {}", spawn, e);
+        }
+    }
+    for (player, entity, cmd) in effects.commands {
+        unit_events.send(UnitInteractionEvent::Ui(player, entity, cmd));
+    }
+    for (t1, t2, rel) in effects.relations {
+        teams.set_relation(t1, t2, rel);
+    }
+    for msg in effects.messages {
+        log::info!("{}", msg);
+        messages.send(ScenarioMessage(msg));
+    }
+    if let Some(winner) = effects.end_battle {
+        battle.end_battle(winner);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::victory::VictoryCondition;
+
+    #[test]
+    fn test_trigger_conditions() {
+        let unit = |player: PlayerId, name: Option<&str>, x: f32, health: f32| UnitSnapshot {
+            player,
+            name: name.map(|n| n.to_string()),
+            position: XyPos::new(x, 0.0),
+            health,
+            routing: false,
+        };
+        let alive = vec![
+            unit(1, Some("general"), 0.0, 0.3),
+            unit(2, None, 100.0, 1.0),
+        ];
+        let died = vec![unit(2, Some("scout"), 50.0, 0.0)];
+
+        let general = UnitSelector::Named("general".to_string());
+        assert!(TriggerCondition::HealthBelow(general.clone(), 0.5).is_met(0.0, &alive, &died));
+        assert!(
+            !TriggerCondition::HealthBelow(UnitSelector::Player(2), 0.5).is_met(0.0, &alive, &died)
+        );
+        assert!(TriggerCondition::UnitDied(UnitSelector::Player(2)).is_met(0.0, &alive, &died));
+        assert!(!TriggerCondition::UnitDied(general).is_met(0.0, &alive, &died));
+
+        let area = Area::new(XyPos::new(90.0, -10.0), XyPos::new(110.0, 10.0));
+        assert!(TriggerCondition::UnitInArea(UnitSelector::Any, area).is_met(0.0, &alive, &died));
+        assert!(!TriggerCondition::TimeElapsed(10.0).is_met(5.0, &alive, &died));
+    }
+
+    #[test]
+    fn test_trigger_actions() {
+        let unit = |player: PlayerId| UnitSnapshot {
+            player,
+            name: None,
+            position: XyPos::new(0.0, 0.0),
+            health: 1.0,
+            routing: false,
+        };
+        let (ours, theirs) = (Entity::new(1), Entity::new(2));
+        let alive = vec![(ours, unit(1)), (theirs, unit(2))];
+
+        let triggers = vec![
+            Trigger {
+                condition: TriggerCondition::TimeElapsed(10.0),
+                actions: vec![
+                    TriggerAction::SpawnUnits(vec![UnitSpawn::new(
+                        UnitType::MeleeInfantry,
+                        2,
+                        0.0,
+                        0.0,
+                        16,
+                    )]),
+                    TriggerAction::IssueCommand(UnitSelector::Player(2), UnitUiCommand::Stop),
+                ],
+            },
+            Trigger {
+                condition: TriggerCondition::UnitDied(UnitSelector::Player(2)),
+                actions: vec![TriggerAction::EndBattle(Some(0))],
+            },
+        ];
+
+        let mut state = TriggerState::default();
+        let died = state.record(&alive, &[]);
+        let effects = state.fire(&triggers, 5.0, &alive, &died);
+        assert!(effects.spawns.is_empty() && effects.commands.is_empty());
+
+        // commands are issued by the owner of the unit, and each trigger only fires once
+        let effects = state.fire(&triggers, 10.0, &alive, &died);
+        assert_eq!(effects.spawns.len(), 1);
+        assert_eq!(effects.spawns[0].player, 2);
+        assert_eq!(effects.commands.len(), 1);
+        assert!(matches!(
+            effects.commands[0],
+            (2, e, UnitUiCommand::Stop) if e == theirs
+        ));
+        assert!(state
+            .fire(&triggers, 11.0, &alive, &died)
+            .commands
+            .is_empty());
+        assert_eq!(effects.end_battle, None);
+
+        // the dead unit is picked up from its last known state, then forgotten
+        let alive = vec![(ours, unit(1))];
+        let died = state.record(&alive, &[theirs]);
+        assert_eq!(died.len(), 1);
+        assert!(!state.known.contains_key(&theirs));
+
+        // ending the battle from a trigger decides it on the next victory check
+        let effects = state.fire(&triggers, 12.0, &alive, &died);
+        assert_eq!(effects.end_battle, Some(Some(0)));
+        let mut battle = BattleResource::new(vec![VictoryCondition::Annihilation]);
+        battle.end_battle(effects.end_battle.unwrap());
+        let teams = TeamsResource::from_setup(&TeamSetup::free_for_all(&[1, 2]));
+        assert_eq!(
+            battle.decide(&HashMap::new(), &teams),
+            Some((VictoryCondition::Scripted, Some(0)))
+        );
+        assert_eq!(battle.decide(&HashMap::new(), &teams), None);
+    }
+}
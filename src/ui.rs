@@ -2,6 +2,10 @@
 
 use bevy::prelude::*;
 
+use crate::abilities::AbilitiesComponent;
+use crate::combat::ActiveMatchups;
+use crate::triggers::ScenarioMessage;
+use crate::victory::BattleResource;
 use crate::{HealthComponent, UnitComponent, UnitUiState};
 
 pub const ICON_SCALE: f32 = 1.2;
@@ -25,15 +29,22 @@ pub struct UiStateMaterials {
     pub moving_fast: Handle<ColorMaterial>,
     pub melee: Handle<ColorMaterial>,
     pub firing: Handle<ColorMaterial>,
+    pub ability: Handle<ColorMaterial>,
 }
 
 pub fn state_icon_system(
     icon_materials: Res<UiStateMaterials>,
-    unit_query: Query<(&UnitComponent, &Children)>,
+    unit_query: Query<(&UnitComponent, &Children, Option<&AbilitiesComponent>)>,
     mut icon_query: Query<&mut Handle<ColorMaterial>>,
 ) {
-    for (unit, children) in unit_query.iter() {
+    for (unit, children, abilities) in unit_query.iter() {
         if let Ok(mut state_icon) = icon_query.get_mut(children[0]) {
+            // an active ability takes priority over the units state
+            if abilities.map(|a| a.active.is_some()).unwrap_or(false) {
+                *state_icon = icon_materials.ability.clone();
+                continue;
+            }
+
             *state_icon = match unit.ui_state() {
                 UnitUiState::MovingSlow => icon_materials.moving.clone(),
                 UnitUiState::MovingFast => icon_materials.moving_fast.clone(),
@@ -45,6 +56,67 @@ pub fn state_icon_system(
     }
 }
 
+/// marker for the debug text that lists matchup bonuses active this tick
+pub struct MatchupOverlayText;
+
+pub fn matchup_overlay_system(
+    active_matchups: Res<ActiveMatchups>,
+    mut query: Query<&mut Text, With<MatchupOverlayText>>,
+) {
+    for mut text in query.iter_mut() {
+        text.value = active_matchups
+            .0
+            .iter()
+            .map(|m| {
+                format!(
+                    "{:?} vs {:?}: +{} atk, +{} dmg",
+                    m.attacker_type, m.defender_type, m.bonus.melee_attack, m.bonus.damage
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+}
+
+/// marker for the text that announces the end of the battle
+pub struct BattleOutcomeText;
+
+pub fn battle_outcome_text_system(
+    battle: Res<BattleResource>,
+    mut query: Query<&mut Text, With<BattleOutcomeText>>,
+) {
+    let outcome = match &battle.outcome {
+        Some(outcome) => outcome,
+        None => return,
+    };
+    for mut text in query.iter_mut() {
+        let result = match outcome.winner {
+            Some(team) if outcome.allies.is_empty() => format!("Team {} wins", team),
+            Some(team) => format!("Team {} and allies {:?} win", team, outcome.allies),
+            None => "Draw".to_string(),
+        };
+        text.value = format!(
+            "{} ({:?} after {:.0}s)",
+            result, outcome.condition, outcome.elapsed
+        );
+    }
+}
+
+/// marker for the text that shows the latest scenario message
+pub struct MessageText;
+
+pub fn message_text_system(
+    mut reader: Local<EventReader<ScenarioMessage>>,
+    messages: Res<Events<ScenarioMessage>>,
+    mut query: Query<&mut Text, With<MessageText>>,
+) {
+    if let Some(msg) = reader.iter(&messages).last() {
+        for mut text in query.iter_mut() {
+            text.value = msg.0.clone();
+        }
+    }
+}
+
 pub fn healthbar_system(
     healthbar_materials: Res<HeathBarMaterials>,
     mut unit_query: Query<(&HealthComponent, &Children)>,
@@ -1,20 +1,132 @@
+use std::collections::HashMap;
+
 use bevy::prelude::*;
 
 use bevy_rapier2d::physics::{ColliderHandleComponent, RigidBodyHandleComponent};
 use bevy_rapier2d::rapier::dynamics::RigidBodySet;
 use bevy_rapier2d::rapier::geometry::ColliderSet;
-use bevy_rapier2d::rapier::math::Isometry;
+use bevy_rapier2d::rapier::math::{Isometry, Vector};
 
+use crate::abilities::*;
+use crate::formation::*;
+use crate::line_of_sight::*;
+use crate::objectives::CapturePointComponent;
 use crate::physics::*;
+use crate::pursuit::PursuitComponent;
+use crate::soldiers::*;
+use crate::teams::TeamsResource;
 
 use crate::*;
 
+/// morale regained each second by units with a friendly unit at their side
+const SUPPORT_MORALE_RECOVERY: f32 = 2.0;
+
 /// stores units that are within attack (melee or missle) range of the
 /// associated unit, used to determining unit behaviour.
 #[derive(Debug, Default)]
 pub struct NearbyUnitsComponent {
     melee_range: Vec<Entity>,
     missle_range: Vec<Entity>,
+    /// units in missile range that the unit is willing to shoot at, most preferred first
+    missile_targets: Vec<Entity>,
+    /// units in melee range, most preferred first
+    melee_targets: Vec<Entity>,
+    /// non-hostile units in contact with this one. Never attacked, but they steady
+    /// the unit's morale, see `support_morale_system`.
+    friendly_contacts: Vec<Entity>,
+    /// non-hostile units in missile range. Never shot at, but kept in case they become enemies
+    friendly_in_range: Vec<Entity>,
+}
+
+impl NearbyUnitsComponent {
+    pub fn melee_range(&self) -> &Vec<Entity> {
+        &self.melee_range
+    }
+
+    pub fn missile_range(&self) -> &Vec<Entity> {
+        &self.missle_range
+    }
+
+    pub fn friendly_contacts(&self) -> &Vec<Entity> {
+        &self.friendly_contacts
+    }
+
+    pub fn set_missile_targets(&mut self, targets: Vec<Entity>) {
+        self.missile_targets = targets;
+    }
+
+    pub fn set_melee_targets(&mut self, targets: Vec<Entity>) {
+        self.melee_targets = targets;
+    }
+
+    /// re-sorts contacts after a change in team relations. Units touching this one and units
+    /// in missile range are split into foes and friends again, so units that are no longer
+    /// hostile can't be attacked, and new enemies can be straight away.
+    pub fn reclassify(&mut self, is_hostile: &dyn Fn(Entity) -> bool) {
+        let split = |foes: &Vec<Entity>, friends: &Vec<Entity>| -> (Vec<Entity>, Vec<Entity>) {
+            foes.iter()
+                .chain(friends.iter())
+                .cloned()
+                .partition(|e| is_hostile(*e))
+        };
+        let (hostile, friendly) = split(&self.melee_range, &self.friendly_contacts);
+        self.melee_range = hostile;
+        self.friendly_contacts = friendly;
+        let (hostile, friendly) = split(&self.missle_range, &self.friendly_in_range);
+        self.missle_range = hostile;
+        self.friendly_in_range = friendly;
+        self.missile_targets.retain(|e| is_hostile(*e));
+        self.melee_targets.retain(|e| is_hostile(*e));
+    }
+}
+
+/// proximity events only sort a contact into foe or friend when it is made, so existing
+/// contacts are re-sorted whenever the team relations change, eg from a scenario trigger.
+pub fn reclassify_contacts_system(
+    teams: ChangedRes<TeamsResource>,
+    units: Query<&UnitComponent>,
+    mut nearbys: Query<(Entity, &mut NearbyUnitsComponent)>,
+    mut soldiers: Query<(Entity, &mut SoldierComponent)>,
+) {
+    let soldier_units: HashMap<Entity, Entity> =
+        soldiers.iter_mut().map(|(e, s)| (e, s.unit)).collect();
+    // soldiers belong to the player that owns their unit
+    let player = |e: Entity| {
+        let unit = soldier_units.get(&e).cloned().unwrap_or(e);
+        units
+            .get_component::<UnitComponent>(unit)
+            .ok()
+            .map(|u| u.player_id)
+    };
+    let hostile = |e1: Entity, e2: Entity| match (player(e1), player(e2)) {
+        (Some(p1), Some(p2)) => teams.is_foe(p1, p2),
+        _ => false,
+    };
+
+    for (entity, mut nearby) in nearbys.iter_mut() {
+        nearby.reclassify(&|other| hostile(entity, other));
+    }
+    for (entity, mut soldier) in soldiers.iter_mut() {
+        soldier.reclassify(&|other| hostile(entity, other));
+    }
+}
+
+/// units with friends at their side steady themselves, slowly regaining morale lost to
+/// flank and rear attacks
+pub fn support_morale_system(
+    game_speed: Res<GameSpeed>,
+    time: Res<Time>,
+    mut units: Query<(&NearbyUnitsComponent, &mut MoraleComponent)>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    for (nearby, mut morale) in units.iter_mut() {
+        if !nearby.friendly_contacts().is_empty() {
+            morale.recover(SUPPORT_MORALE_RECOVERY * time.delta_seconds());
+        }
+    }
 }
 
 /// helper function
@@ -37,20 +149,155 @@ fn process_unit_proximity(
     };
 
     if e_or_e == EnterOrExit::Enter {
-        vec.push(target_id);
+        if !vec.contains(&target_id) {
+            vec.push(target_id);
+        }
     } else {
         vec.retain(|e| e != &target_id);
     }
 }
 
+/// helper function
+fn process_friendly_proximity(
+    unit_id: Entity,
+    friend_id: Entity,
+    nearbys: &mut Query<&mut NearbyUnitsComponent>,
+    e_or_e: EnterOrExit,
+) {
+    if let Ok(mut nbs) = nearbys.get_component_mut::<NearbyUnitsComponent>(unit_id) {
+        if e_or_e == EnterOrExit::Enter {
+            if !nbs.friendly_contacts.contains(&friend_id) {
+                nbs.friendly_contacts.push(friend_id);
+            }
+        } else {
+            nbs.friendly_contacts.retain(|e| e != &friend_id);
+        }
+    }
+}
+
+/// helper function
+fn process_friendly_in_range(
+    unit_id: Entity,
+    target_id: Entity,
+    nearbys: &mut Query<&mut NearbyUnitsComponent>,
+    e_or_e: EnterOrExit,
+) {
+    if let Ok(mut nbs) = nearbys.get_component_mut::<NearbyUnitsComponent>(unit_id) {
+        if e_or_e == EnterOrExit::Enter {
+            if !nbs.friendly_in_range.contains(&target_id) {
+                nbs.friendly_in_range.push(target_id);
+            }
+        } else {
+            nbs.friendly_in_range.retain(|e| e != &target_id);
+        }
+    }
+}
+
+/// helper function
+/// single-body units don't track soldiers, so either side may not be a soldier
+fn process_soldier_friendly_proximity(
+    soldier_id: Entity,
+    other_id: Entity,
+    soldiers: &mut Query<&mut SoldierComponent>,
+    e_or_e: EnterOrExit,
+) {
+    if let Ok(mut soldier) = soldiers.get_component_mut::<SoldierComponent>(soldier_id) {
+        if e_or_e == EnterOrExit::Enter {
+            if !soldier.friendly_contacts.contains(&other_id) {
+                soldier.friendly_contacts.push(other_id);
+            }
+        } else {
+            soldier.friendly_contacts.retain(|e| e != &other_id);
+        }
+    }
+}
+
+/// helper function
+fn process_soldier_proximity(
+    soldier_id: Entity,
+    target_id: Entity,
+    soldiers: &mut Query<&mut SoldierComponent>,
+    e_or_e: EnterOrExit,
+) {
+    if let Ok(mut soldier) = soldiers.get_component_mut::<SoldierComponent>(soldier_id) {
+        if e_or_e == EnterOrExit::Enter {
+            if !soldier.melee_range.contains(&target_id) {
+                soldier.melee_range.push(target_id);
+            }
+        } else {
+            soldier.melee_range.retain(|e| e != &target_id);
+        }
+    }
+}
+
+/// forgets every contact made with a units melee collider, for when it is replaced.
+/// Rapier doesn't report exits for a removed collider, and the new one reports everything
+/// it overlaps as a fresh contact.
+pub fn forget_unit_contacts(
+    unit_id: Entity,
+    nearbys: &mut Query<(Entity, &mut NearbyUnitsComponent)>,
+    soldiers: &mut Query<&mut SoldierComponent>,
+    capture_points: &mut Query<&mut CapturePointComponent>,
+) {
+    for (entity, mut nearby) in nearbys.iter_mut() {
+        if entity == unit_id {
+            nearby.melee_range.clear();
+            nearby.melee_targets.clear();
+            nearby.friendly_contacts.clear();
+        } else {
+            nearby.melee_range.retain(|e| e != &unit_id);
+            nearby.missle_range.retain(|e| e != &unit_id);
+            nearby.missile_targets.retain(|e| e != &unit_id);
+            nearby.melee_targets.retain(|e| e != &unit_id);
+            nearby.friendly_contacts.retain(|e| e != &unit_id);
+            nearby.friendly_in_range.retain(|e| e != &unit_id);
+        }
+    }
+    for mut soldier in soldiers.iter_mut() {
+        soldier.forget(unit_id);
+    }
+    for mut point in capture_points.iter_mut() {
+        point.unit_left(unit_id);
+    }
+}
+
 /// helper function
 fn process_unit_command(
     unit_id: Entity,
     cmd: UnitUiCommand,
     units: &mut Query<&mut UnitComponent>,
+    formations: &mut Query<&mut FormationComponent>,
+    abilities: &mut Query<&mut AbilitiesComponent>,
+    missiles: &mut Query<&mut MissileWeaponComponent>,
+    pursuits: &mut Query<&mut PursuitComponent>,
 ) {
     use UnitUiCommand::*;
+
+    // a new order replaces any pursuit the unit was on, and any throw it was going to make
+    let new_orders = matches!(
+        cmd,
+        Attack(..) | AttackSecondary(_) | Move(..) | Formation { .. } | Stop
+    );
+    if new_orders {
+        if let Ok(mut pursuit) = pursuits.get_component_mut::<PursuitComponent>(unit_id) {
+            pursuit.cancel();
+        }
+    }
+    let has_secondary = matches!(
+        missiles.get_component::<MissileWeaponComponent>(unit_id),
+        Ok(MissileWeaponComponent::Secondary(_))
+    );
+
     let mut unit = units.get_component_mut::<UnitComponent>(unit_id).unwrap();
+    if new_orders {
+        unit.throw_secondary = false;
+    }
+    let mut formation = formations
+        .get_component_mut::<FormationComponent>(unit_id)
+        .ok();
+    let mut unit_abilities = abilities
+        .get_component_mut::<AbilitiesComponent>(unit_id)
+        .ok();
     match cmd {
         Attack(target, speed) => {
             unit.is_running = speed == UnitUiSpeedCommand::Run;
@@ -58,18 +305,74 @@ fn process_unit_command(
                 UnitUserCommand::AttackMelee(target)
             } else {
                 UnitUserCommand::AttackMissile(target)
+            };
+            // with fire at will on, units throw their secondary missiles before charging in
+            unit.throw_secondary = has_secondary && unit.fire_at_will;
+        }
+        AttackSecondary(target) => {
+            if has_secondary {
+                // a melee attack with a throw first, so the unit still charges once the
+                // missiles are spent
+                unit.current_command = UnitUserCommand::AttackMelee(target);
+                unit.throw_secondary = true;
+            } else {
+                log::debug!("unit has no secondary missile weapon");
             }
         }
         Move(pos, speed) => {
             unit.current_command = UnitUserCommand::Move(pos);
             unit.is_running = speed == UnitUiSpeedCommand::Run;
+            if let Some(formation) = formation.as_mut() {
+                formation.final_facing = None;
+            }
+        }
+        Formation {
+            position,
+            facing,
+            frontage,
+            speed,
+        } => {
+            unit.current_command = UnitUserCommand::Move(position);
+            unit.is_running = speed == UnitUiSpeedCommand::Run;
+            if let Some(formation) = formation.as_mut() {
+                formation.set_frontage(frontage);
+                formation.final_facing = Some(facing);
+            }
+        }
+        ToggleSpacing => {
+            if let Some(formation) = formation.as_mut() {
+                formation.spacing = formation.spacing.toggle();
+            }
+        }
+        ToggleFireMode => {
+            if let Ok(mut missile) = missiles.get_component_mut::<MissileWeaponComponent>(unit_id) {
+                if let Some(stats) = missile.stats_mut() {
+                    stats.fire_mode = stats.fire_mode.toggle();
+                }
+            }
         }
         Stop => {
             unit.current_command = UnitUserCommand::None_;
         }
+        SetTargetingPolicy(policy) => unit.targeting_policy = policy,
         ToggleGuardMode => unit.guard_mode_enabled = !unit.guard_mode_enabled,
         ToggleFireAtWill => unit.fire_at_will = !unit.fire_at_will,
         ToggleSpeed => unit.is_running = !unit.is_running,
+        ActivateAbility(ability) => {
+            if let Some(unit_abilities) = unit_abilities.as_mut() {
+                unit_abilities.activate(ability);
+            }
+        }
+        ToggleAbility(ability) => {
+            if let Some(unit_abilities) = unit_abilities.as_mut() {
+                unit_abilities.toggle(ability);
+            }
+        }
+        DeactivateAbility => {
+            if let Some(unit_abilities) = unit_abilities.as_mut() {
+                unit_abilities.deactivate();
+            }
+        }
     }
     log::debug!("unit current command: {:?}", unit.current_command);
 }
@@ -83,20 +386,41 @@ pub fn unit_event_system(
     game_speed: Res<GameSpeed>,
     mut state: Local<UnitInteractionState>,
     events: Res<Events<UnitInteractionEvent>>,
+    mut rejections: ResMut<Events<CommandRejectedEvent>>,
     mut units: Query<&mut UnitComponent>,
     mut nearbys: Query<&mut NearbyUnitsComponent>,
+    mut soldiers: Query<&mut SoldierComponent>,
+    squads: Query<&SoldiersComponent>,
+    mut formations: Query<&mut FormationComponent>,
+    mut abilities: Query<&mut AbilitiesComponent>,
+    mut missiles: Query<&mut MissileWeaponComponent>,
+    mut pursuits: Query<&mut PursuitComponent>,
+    mut capture_points: Query<&mut CapturePointComponent>,
 ) {
-    // TODO maybe this should be running?
-    if game_speed.is_paused() {
-        return;
-    }
+    // contacts are always kept up to date, as units can be moved while paused (eg during
+    // deployment) and rapier won't report them again. Everything else waits for the game to
+    // resume, or is dropped once the battle is over.
+    let new_events: Vec<UnitInteractionEvent> = state.event_reader.iter(&events).cloned().collect();
+    let events = if game_speed.is_paused() {
+        let (contacts, held): (Vec<_>, Vec<_>) = new_events
+            .into_iter()
+            .partition(|e| matches!(e, UnitInteractionEvent::Proximity(_)));
+        if !game_speed.is_stopped() {
+            state.held_events.extend(held);
+        }
+        contacts
+    } else {
+        let mut held = std::mem::take(&mut state.held_events);
+        held.extend(new_events);
+        held
+    };
 
     let mut dead_units = vec![];
 
     // process state updates for units that have new events
-    for event in state.event_reader.iter(&events) {
+    for event in events {
         log::debug!("event: {:?}", &event);
-        match event.clone() {
+        match event {
             UnitInteractionEvent::Proximity(contact) => {
                 match contact {
                     ContactType::UnitFiringRangeEnter { range_of, target } => {
@@ -150,10 +474,92 @@ pub fn unit_event_system(
                             AttackType::Melee,
                         );
                     }
+                    ContactType::UnitUnitFriendlyEnter(e1, e2)
+                    | ContactType::UnitUnitFriendlyExit(e1, e2) => {
+                        process_friendly_proximity(e1, e2, &mut nearbys, contact.enter_or_exit());
+                        process_friendly_proximity(e2, e1, &mut nearbys, contact.enter_or_exit());
+                    }
+                    ContactType::UnitFiringRangeFriendlyEnter { range_of, target }
+                    | ContactType::UnitFiringRangeFriendlyExit { range_of, target } => {
+                        process_friendly_in_range(
+                            range_of,
+                            target,
+                            &mut nearbys,
+                            contact.enter_or_exit(),
+                        );
+                    }
+                    ContactType::CapturePointEnter { point, unit } => {
+                        if let Ok(mut point) =
+                            capture_points.get_component_mut::<CapturePointComponent>(point)
+                        {
+                            point.unit_entered(unit);
+                        }
+                    }
+                    ContactType::CapturePointExit { point, unit } => {
+                        if let Ok(mut point) =
+                            capture_points.get_component_mut::<CapturePointComponent>(point)
+                        {
+                            point.unit_left(unit);
+                        }
+                    }
+                    ContactType::SoldierSoldierMeleeEnter(s1, s2)
+                    | ContactType::SoldierSoldierMeleeExit(s1, s2) => {
+                        process_soldier_proximity(s1, s2, &mut soldiers, contact.enter_or_exit());
+                        process_soldier_proximity(s2, s1, &mut soldiers, contact.enter_or_exit());
+                    }
+                    ContactType::SoldierUnitMeleeEnter { soldier, unit }
+                    | ContactType::SoldierUnitMeleeExit { soldier, unit } => {
+                        process_soldier_proximity(
+                            soldier,
+                            unit,
+                            &mut soldiers,
+                            contact.enter_or_exit(),
+                        );
+                    }
+                    ContactType::SoldierFriendlyEnter(e1, e2)
+                    | ContactType::SoldierFriendlyExit(e1, e2) => {
+                        process_soldier_friendly_proximity(
+                            e1,
+                            e2,
+                            &mut soldiers,
+                            contact.enter_or_exit(),
+                        );
+                        process_soldier_friendly_proximity(
+                            e2,
+                            e1,
+                            &mut soldiers,
+                            contact.enter_or_exit(),
+                        );
+                    }
                 }
             }
-            UnitInteractionEvent::Ui(entity, cmd) => {
-                process_unit_command(entity, cmd, &mut units);
+            UnitInteractionEvent::Ui(player, entity, cmd) => {
+                let error = match units.get_component::<UnitComponent>(entity) {
+                    Ok(unit) if unit.player_id == player => None,
+                    Ok(unit) => Some(CommandError::NotOwner {
+                        owner: unit.player_id,
+                    }),
+                    Err(_) => Some(CommandError::UnknownUnit),
+                };
+                if let Some(error) = error {
+                    log::warn!("player {} can't command {:?}: {:?}", player, entity, error);
+                    rejections.send(CommandRejectedEvent {
+                        player,
+                        unit: entity,
+                        cmd,
+                        error,
+                    });
+                    continue;
+                }
+                process_unit_command(
+                    entity,
+                    cmd,
+                    &mut units,
+                    &mut formations,
+                    &mut abilities,
+                    &mut missiles,
+                    &mut pursuits,
+                );
             }
             UnitInteractionEvent::UnitWaypointReached(e1) => {
                 let mut unit = units.get_component_mut::<UnitComponent>(e1).unwrap();
@@ -192,22 +598,45 @@ pub fn unit_event_system(
         for dead in dead_units.iter() {
             nearby.melee_range.retain(|e| e != dead);
             nearby.missle_range.retain(|e| e != dead);
+            nearby.missile_targets.retain(|e| e != dead);
+            nearby.melee_targets.retain(|e| e != dead);
+            nearby.friendly_contacts.retain(|e| e != dead);
+            nearby.friendly_in_range.retain(|e| e != dead);
+        }
+    }
+
+    for mut point in capture_points.iter_mut() {
+        for dead in dead_units.iter() {
+            point.unit_left(dead.clone());
+        }
+    }
+
+    // soldiers aren't children of their unit, so any that are left go with it
+    let dead_soldiers: Vec<Entity> = dead_units
+        .iter()
+        .filter_map(|dead| squads.get_component::<SoldiersComponent>(*dead).ok())
+        .flat_map(|squad| squad.soldiers.iter().cloned())
+        .collect();
+
+    for mut soldier in soldiers.iter_mut() {
+        for dead in dead_units.iter().chain(dead_soldiers.iter()) {
+            soldier.forget(*dead);
         }
     }
 
-    for dead in dead_units.iter() {
+    for dead in dead_units.iter().chain(dead_soldiers.iter()) {
         commands.despawn_recursive(dead.clone());
     }
 }
 
-/// Returns None if no targets available
-/// TODO make this fancier
+/// Returns None if no targets available.
+/// Targets are already in order of preference, see `missile_targeting_system`
 pub fn pick_missile_target(available_targets: &Vec<Entity>) -> Option<Entity> {
     available_targets.get(0).map(|e| e.clone())
 }
 
-/// Returns None if no targets available
-/// TODO make this fancier
+/// Returns None if no targets available.
+/// Targets are already in order of preference, see `melee_targeting_system`
 pub fn pick_melee_target(available_targets: &Vec<Entity>) -> Option<Entity> {
     available_targets.get(0).map(|e| e.clone())
 }
@@ -218,7 +647,9 @@ pub fn calculate_next_unit_state_and_target(
     enemies_within_missile_range: &Vec<Entity>,
     guard_mode_enabled: bool,
     fire_at_will_enabled: bool,
+    // primary missile weapons only, secondary ones are only thrown on an attack order
     missile_attack_available: bool,
+    secondary_missile_ready: bool,
     can_fire_while_moving: bool,
     currently_fighting: Option<Entity>,
 ) -> UnitState {
@@ -237,14 +668,14 @@ pub fn calculate_next_unit_state_and_target(
             } else if let Some(next_target) = next_melee_target() {
                 // pick someone else
                 UnitState::Melee(Some(next_target))
+            } else if secondary_missile_ready && enemies_within_missile_range.contains(&cmd_target)
+            {
+                // throw before charging in, see `UnitComponent::throw_secondary`
+                UnitState::Firing(Some(cmd_target.clone()))
             } else {
-                // no one else nearby, target still alive outside of melee range
-                if guard_mode_enabled {
-                    UnitState::Moving
-                } else {
-                    // TODO clear command
-                    UnitState::Idle
-                }
+                // no one else nearby, target still alive outside of melee range. Either we
+                // haven't reached it yet, or it fled and we are pursuing, see `pursuit_system`
+                UnitState::Moving
             }
         }
         UnitUserCommand::AttackMissile(cmd_target) => {
@@ -314,20 +745,36 @@ pub fn unit_state_machine_system(
         &mut UnitComponent,
         &NearbyUnitsComponent,
         &MissileWeaponComponent,
+        Option<&LineOfSightComponent>,
     )>,
 ) {
     if game_speed.is_paused() {
         return;
     }
 
-    for (mut unit, nearbys, missile) in units.iter_mut() {
+    for (mut unit, nearbys, missile, los) in units.iter_mut() {
+        // can't shoot at what we can't see
+        let missile_targets = los
+            .map(|los| los.visible(&nearbys.missile_targets))
+            .unwrap_or_else(|| nearbys.missile_targets.clone());
+
+        let (missile_attack_available, secondary_missile_ready) = match missile {
+            MissileWeaponComponent::Primary(_) => (missile.is_missile_attack_available(), false),
+            MissileWeaponComponent::Secondary(_) => (
+                false,
+                unit.throw_secondary && missile.is_missile_attack_available(),
+            ),
+            MissileWeaponComponent::None => (false, false),
+        };
+
         let new_state = calculate_next_unit_state_and_target(
             &unit.current_command,
-            &nearbys.melee_range,
-            &nearbys.missle_range,
+            &nearbys.melee_targets,
+            &missile_targets,
             unit.guard_mode_enabled,
             unit.fire_at_will,
-            missile.is_missile_attack_available(),
+            missile_attack_available,
+            secondary_missile_ready,
             unit.can_fire_while_moving(),
             unit.state.current_actively_fighting(),
         );
@@ -345,6 +792,48 @@ pub fn unit_state_machine_system(
     }
 }
 
+/// units with a secondary missile weapon only have a melee collider, so their throwing range
+/// is checked by distance instead of through a firing range sensor
+pub fn secondary_missile_range_system(
+    game_speed: Res<GameSpeed>,
+    teams: Res<TeamsResource>,
+    mut units: Query<(
+        Entity,
+        &UnitComponent,
+        &MissileWeaponComponent,
+        &Transform,
+        &mut NearbyUnitsComponent,
+    )>,
+    targets: Query<(Entity, &UnitComponent, &Transform)>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    for (entity, unit, missile, transform, mut nearbys) in units.iter_mut() {
+        let range = match missile {
+            MissileWeaponComponent::Secondary(stats) => stats.range,
+            _ => continue,
+        };
+        let pos: XyPos = (transform.translation.x, transform.translation.y).into();
+
+        nearbys.missle_range = targets
+            .iter()
+            .filter(|(target, target_unit, target_transform)| {
+                let target_pos: XyPos = (
+                    target_transform.translation.x,
+                    target_transform.translation.y,
+                )
+                    .into();
+                *target != entity
+                    && teams.is_foe(unit.player_id, target_unit.player_id)
+                    && (target_pos - pos).length() <= range
+            })
+            .map(|(target, _, _)| target)
+            .collect();
+    }
+}
+
 /// for each unit, calculates the position of its waypoint
 pub fn unit_waypoint_system(
     game_speed: Res<GameSpeed>,
@@ -375,34 +864,136 @@ pub fn unit_waypoint_system(
     }
 }
 
+/// the result of advancing a unit by one fixed movement step
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovementStep {
+    pub position: XyPos,
+    pub facing: XyPos,
+    pub speed: f32,
+    pub arrived: bool,
+}
+
+/// advances a unit towards `dest` by one step of `dt` seconds.
+/// The unit turns towards the destination at no more than its turn rate, slows down
+/// while it isn't pointing where it wants to go, and brakes so that it can stop at the destination.
+/// This is a pure function so that movement is deterministic for a fixed `dt`.
+pub fn step_movement(
+    unit: &UnitComponent,
+    position: XyPos,
+    facing: XyPos,
+    speed: f32,
+    dest: XyPos,
+    dt: f32,
+) -> MovementStep {
+    let relative_position = dest - position;
+    let distance = relative_position.length();
+
+    // can reach destination this step
+    if distance <= (speed * dt).max(ARRIVAL_DISTANCE) {
+        return MovementStep {
+            position: dest,
+            facing,
+            speed: 0.0,
+            arrived: true,
+        };
+    }
+
+    let desired_direction = relative_position / distance;
+
+    // signed angle from current facing to the desired direction
+    let angle = (facing.x * desired_direction.y - facing.y * desired_direction.x)
+        .atan2(facing.dot(desired_direction));
+    let max_turn = unit.turn_rate() * dt;
+    let turn = angle.max(-max_turn).min(max_turn);
+    let (sin, cos) = turn.sin_cos();
+    let new_facing = XyPos::new(
+        facing.x * cos - facing.y * sin,
+        facing.x * sin + facing.y * cos,
+    );
+
+    // slow down while wheeling around, and brake in time to stop at the destination
+    let alignment = (angle - turn).cos().max(0.0);
+    let braking_speed = (2.0 * unit.deceleration() * distance).sqrt();
+    let target_speed = unit.current_speed().min(braking_speed) * alignment;
+
+    let new_speed = if speed < target_speed {
+        (speed + unit.acceleration() * dt).min(target_speed)
+    } else {
+        (speed - unit.deceleration() * dt).max(target_speed)
+    };
+
+    MovementStep {
+        position: position + new_facing * new_speed * dt,
+        facing: new_facing,
+        speed: new_speed,
+        arrived: false,
+    }
+}
+
+/// slows a unit that has nowhere to go by one step of `dt` seconds, at its braking rate.
+/// It carries on in the direction it is facing until it comes to a stop.
+pub fn step_braking(
+    unit: &UnitComponent,
+    position: XyPos,
+    facing: XyPos,
+    speed: f32,
+    dt: f32,
+) -> MovementStep {
+    let new_speed = (speed - unit.deceleration() * dt).max(0.0);
+    MovementStep {
+        position: position + facing * new_speed * dt,
+        facing,
+        speed: new_speed,
+        arrived: new_speed == 0.0,
+    }
+}
+
+/// leftover frame time that hasn't been consumed by a fixed movement step yet
+#[derive(Default)]
+pub struct MovementTimestepAccumulator(f32);
+
 // TODO have a separate component for waypoint position for all command types
 // that is updated in a separate system, so its calculated separately from the unit movement system
 // so we don't run into unique borrow issues
 pub fn unit_movement_system(
     game_speed: Res<GameSpeed>,
     time: Res<Time>,
+    mut accumulator: Local<MovementTimestepAccumulator>,
     mut bodies: ResMut<RigidBodySet>,
     mut colliders: ResMut<ColliderSet>,
     mut unit_events: ResMut<Events<UnitInteractionEvent>>,
     mut unit_query: Query<(
         Entity,
         &mut UnitComponent,
-        &mut Transform,
         &mut RigidBodyHandleComponent,
         &mut ColliderHandleComponent,
         &WaypointComponent,
+        &mut FacingComponent,
+        &mut VelocityComponent,
+        Option<&mut FormationComponent>,
     )>,
 ) {
     if game_speed.is_paused() {
         return;
     }
 
-    for (entity, unit, transform, body_handle, collider_handle, waypoint) in unit_query.iter_mut() {
-        let translation = transform.translation;
-
-        // TODO remove transform here, use rigid body pos
-        let unit_pos: XyPos = (translation.x, translation.y).into();
+    // movement is stepped at a fixed rate regardless of framerate, so that it is deterministic
+    accumulator.0 += time.delta_seconds();
+    let steps = (accumulator.0 / MOVEMENT_TIMESTEP).floor();
+    accumulator.0 -= steps * MOVEMENT_TIMESTEP;
+    let steps = steps as usize;
 
+    for (
+        entity,
+        unit,
+        body_handle,
+        collider_handle,
+        waypoint,
+        mut facing,
+        mut velocity,
+        mut formation,
+    ) in unit_query.iter_mut()
+    {
         let mut body = bodies.get_mut(body_handle.handle()).expect("body");
         let collider = colliders
             .get_mut(collider_handle.handle())
@@ -429,33 +1020,79 @@ pub fn unit_movement_system(
                 }
                 UnitUserCommand::None_ => None,
             } {
-                let relative_position = dest.clone() - unit_pos;
+                let mut unit_pos: XyPos = (
+                    body.position().translation.vector.x,
+                    body.position().translation.vector.y,
+                )
+                    .into();
 
-                let unit_distance = unit.current_speed() * time.delta_seconds();
+                for _ in 0..steps {
+                    let step = step_movement(
+                        &unit,
+                        unit_pos,
+                        facing.0,
+                        velocity.0,
+                        dest.clone(),
+                        MOVEMENT_TIMESTEP,
+                    );
+                    unit_pos = step.position;
+                    facing.0 = step.facing;
+                    velocity.0 = step.speed;
 
-                // using length_squared() for totally premature optimization
-                let rel_distance_sq = relative_position.length_squared();
+                    if step.arrived {
+                        // wheel into the formations final facing
+                        if let Some(final_facing) =
+                            formation.as_mut().and_then(|f| f.final_facing.take())
+                        {
+                            facing.0 = final_facing;
+                        }
 
-                // if we need to keep moving
-                if unit_distance.powi(2) < rel_distance_sq {
-                    // get direction
-                    let direction = relative_position.normalize();
+                        // reached destination, transition to idle
+                        unit_events.send(UnitInteractionEvent::UnitWaypointReached(entity));
+                        break;
+                    }
+                }
 
-                    // move body
-                    let pos = Isometry::translation(
-                        body.position().translation.vector.x + (direction.x * unit_distance),
-                        body.position().translation.vector.y + (direction.y * unit_distance),
-                    );
+                let pos =
+                    Isometry::new(Vector::new(unit_pos.x, unit_pos.y), facing_angle(facing.0));
+                body.set_position(pos, true);
+                collider.set_position_debug(pos);
+            }
+        } else if velocity.0 > 0.0 {
+            // units that are fighting or have been halted brake to a stop, rather than stopping dead
+            let mut unit_pos: XyPos = (
+                body.position().translation.vector.x,
+                body.position().translation.vector.y,
+            )
+                .into();
+            for _ in 0..steps {
+                let step = step_braking(&unit, unit_pos, facing.0, velocity.0, MOVEMENT_TIMESTEP);
+                unit_pos = step.position;
+                velocity.0 = step.speed;
+            }
 
-                    body.set_position(pos, true);
-                    collider.set_position_debug(pos);
-                } else {
-                    // can reach destination, set position to waypoint, transition to idle
-                    let pos = Isometry::translation(dest.x, dest.y);
-                    body.set_position(pos, true);
-                    collider.set_position_debug(pos);
-                    unit_events.send(UnitInteractionEvent::UnitWaypointReached(entity));
-                }
+            let pos = Isometry::new(Vector::new(unit_pos.x, unit_pos.y), facing_angle(facing.0));
+            body.set_position(pos, true);
+            collider.set_position_debug(pos);
+        }
+    }
+}
+
+/// turns units that are fighting (and not moving) to face their current target
+pub fn unit_facing_system(
+    game_speed: Res<GameSpeed>,
+    mut unit_query: Query<(&UnitComponent, &Transform, &mut FacingComponent)>,
+    target_query: Query<&Transform>,
+) {
+    if game_speed.is_paused() {
+        return;
+    }
+
+    for (unit, transform, mut facing) in unit_query.iter_mut() {
+        if let UnitState::Melee(Some(target)) | UnitState::Firing(Some(target)) = &unit.state {
+            if let Ok(target_transform) = target_query.get_component::<Transform>(target.clone()) {
+                let direction = target_transform.translation - transform.translation;
+                facing.face((direction.x, direction.y).into());
             }
         }
     }
@@ -463,6 +1100,263 @@ pub fn unit_movement_system(
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
+    #[test]
+    fn test_unit_state_machine() {
+        use UnitState::*;
+        use UnitUserCommand::*;
+
+        let (target, other) = (Entity::new(1), Entity::new(2));
+        let next = |cmd: &UnitUserCommand,
+                    melee: &Vec<Entity>,
+                    missile: &Vec<Entity>,
+                    guard: bool,
+                    missile_available: bool,
+                    secondary_ready: bool| {
+            calculate_next_unit_state_and_target(
+                cmd,
+                melee,
+                missile,
+                guard,
+                true,
+                missile_available,
+                secondary_ready,
+                false,
+                None,
+            )
+        };
+
+        // (command, melee range, missile range, guard mode, primary missiles, throw ready, state)
+        let cases = vec![
+            // secondary missiles are thrown at the target before charging in
+            (
+                AttackMelee(target),
+                vec![],
+                vec![target],
+                false,
+                false,
+                true,
+                Firing(Some(target)),
+            ),
+            // and once they've been thrown, or there are none left, the unit charges
+            (
+                AttackMelee(target),
+                vec![],
+                vec![target],
+                false,
+                false,
+                false,
+                Moving,
+            ),
+            (
+                AttackMelee(target),
+                vec![],
+                vec![other],
+                false,
+                false,
+                true,
+                Moving,
+            ),
+            (
+                AttackMelee(target),
+                vec![target],
+                vec![target],
+                false,
+                false,
+                true,
+                Melee(Some(target)),
+            ),
+            // units keep closing on their target whether or not they are in guard mode. Giving
+            // up on a fleeing target is up to `pursuit_system`
+            (
+                AttackMelee(target),
+                vec![],
+                vec![],
+                true,
+                false,
+                false,
+                Moving,
+            ),
+            (
+                AttackMelee(target),
+                vec![],
+                vec![],
+                false,
+                false,
+                false,
+                Moving,
+            ),
+            (
+                AttackMelee(target),
+                vec![other],
+                vec![],
+                true,
+                false,
+                false,
+                Melee(Some(other)),
+            ),
+            // idle units only shoot at will with a primary missile weapon
+            (None_, vec![], vec![other], false, false, false, Idle),
+            (
+                None_,
+                vec![],
+                vec![other],
+                false,
+                true,
+                false,
+                Firing(Some(other)),
+            ),
+        ];
+
+        for (i, (cmd, melee, missile, guard, available, secondary, expected)) in
+            cases.iter().enumerate()
+        {
+            assert_eq!(
+                &next(cmd, melee, missile, *guard, *available, *secondary),
+                expected,
+                "case {}",
+                i
+            );
+        }
+
+        // units keep fighting whoever they were fighting, rather than picking someone new
+        let third = Entity::new(3);
+        assert_eq!(
+            calculate_next_unit_state_and_target(
+                &AttackMelee(target),
+                &vec![other, third],
+                &vec![],
+                false,
+                true,
+                false,
+                false,
+                false,
+                Some(third),
+            ),
+            Melee(Some(third))
+        );
+    }
+
+    #[test]
+    fn test_reclassify_contacts() {
+        let (foe, friend, turncoat) = (Entity::new(1), Entity::new(2), Entity::new(3));
+        let mut nearby = NearbyUnitsComponent {
+            melee_range: vec![foe, turncoat],
+            missle_range: vec![foe, turncoat],
+            friendly_contacts: vec![friend],
+            friendly_in_range: vec![friend],
+            ..Default::default()
+        };
+
+        // the turncoat has made peace, and the friend has declared war
+        nearby.reclassify(&|e| e == foe || e == friend);
+        assert_eq!(nearby.melee_range(), &vec![foe, friend]);
+        assert_eq!(nearby.missile_range(), &vec![foe, friend]);
+        assert_eq!(nearby.friendly_contacts(), &vec![turncoat]);
+        assert_eq!(nearby.friendly_in_range, vec![turncoat]);
+
+        let mut soldier = SoldierComponent::new(Entity::new(4));
+        soldier.melee_range = vec![foe, turncoat];
+        soldier.friendly_contacts = vec![friend];
+        soldier.reclassify(&|e| e == foe || e == friend);
+        assert_eq!(soldier.melee_range, vec![foe, friend]);
+        assert_eq!(soldier.friendly_contacts, vec![turncoat]);
+    }
+
+    #[test]
+    fn test_support_morale_recovery() {
+        let mut morale = MoraleComponent::default();
+        morale.apply_penalty(10.0);
+        morale.recover(SUPPORT_MORALE_RECOVERY);
+        assert_eq!(
+            morale.current_morale,
+            morale.max_morale - 10.0 + SUPPORT_MORALE_RECOVERY
+        );
+
+        // never past the max
+        morale.recover(morale.max_morale);
+        assert_eq!(morale.current_morale, morale.max_morale);
+
+        // routing units have broken and don't rally
+        morale.apply_penalty(morale.max_morale);
+        morale.recover(SUPPORT_MORALE_RECOVERY);
+        assert!(morale.is_routing());
+        assert_eq!(morale.current_morale, 0.0);
+    }
+
+    #[test]
+    fn test_movement_accelerates_and_arrives() {
+        let (unit, _) = UnitComponent::default_from_type(UnitType::MeleeInfantry, 0);
+        let dest = XyPos::new(100.0, 0.0);
+
+        let mut step = MovementStep {
+            position: XyPos::new(0.0, 0.0),
+            facing: XyPos::new(-1.0, 0.0),
+            speed: 0.0,
+            arrived: false,
+        };
+
+        // facing away from the destination, so it has to turn before it gets going
+        step = step_movement(
+            &unit,
+            step.position,
+            step.facing,
+            step.speed,
+            dest,
+            MOVEMENT_TIMESTEP,
+        );
+        assert_eq!(step.speed, 0.0);
+
+        for _ in 0..10_000 {
+            let prev_speed = step.speed;
+            step = step_movement(
+                &unit,
+                step.position,
+                step.facing,
+                step.speed,
+                dest,
+                MOVEMENT_TIMESTEP,
+            );
+            assert!(step.speed <= prev_speed + unit.acceleration() * MOVEMENT_TIMESTEP + 1e-4);
+            if step.arrived {
+                break;
+            }
+        }
+
+        assert!(step.arrived);
+        assert_eq!(step.position, dest);
+    }
+
     #[test]
-    fn test_unit_state_machine() {}
+    fn test_halted_units_brake_to_a_stop() {
+        let (unit, _) = UnitComponent::default_from_type(UnitType::MeleeCalvary, 0);
+        let mut step = MovementStep {
+            position: XyPos::new(0.0, 0.0),
+            facing: XyPos::new(1.0, 0.0),
+            speed: unit.current_speed(),
+            arrived: false,
+        };
+
+        let mut steps = 0;
+        while !step.arrived {
+            let prev = step;
+            step = step_braking(
+                &unit,
+                step.position,
+                step.facing,
+                step.speed,
+                MOVEMENT_TIMESTEP,
+            );
+            assert!(step.speed < prev.speed);
+            assert!(step.speed >= prev.speed - unit.deceleration() * MOVEMENT_TIMESTEP - 1e-4);
+            assert!(step.position.x >= prev.position.x);
+            steps += 1;
+        }
+
+        // it takes more than one step to stop, and it coasts forwards while it does
+        assert!(steps > 1);
+        assert_eq!(step.speed, 0.0);
+        assert!(step.position.x > 0.0);
+    }
 }
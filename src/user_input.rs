@@ -4,6 +4,9 @@ use bevy::prelude::*;
 use bevy_input::keyboard::*;
 use bevy_input::mouse::*;
 
+use crate::abilities::Ability;
+use crate::formation::split_formation_line;
+use crate::teams::LocalPlayer;
 use crate::*;
 
 pub enum MouseCommand {
@@ -14,10 +17,16 @@ pub enum MouseCommand {
     },
     /// attack/move
     Action(XyPos),
+    /// right-click-drag, sets the frontage and facing of the selected units
+    FormationDrag {
+        start: XyPos,
+        end: XyPos,
+    },
 }
 
 const DOUBLE_CLICK_WINDOW: Duration = Duration::from_millis(500);
 const DRAG_SELECT_MIN_BOX: f32 = 100.0;
+const FORMATION_DRAG_MIN_LENGTH: f32 = 20.0;
 
 pub struct InputState {
     keys: EventReader<KeyboardInput>,
@@ -26,10 +35,14 @@ pub struct InputState {
     is_multi_select_on: bool,
     /// ie, shift+select
     is_toggle_select_on: bool,
+    /// ie, alt+rclick to use a secondary missile weapon
+    is_secondary_attack_on: bool,
     /// for double-click events
     last_mouse_action: Option<(Instant, MouseButton)>,
     /// should this be an option?
     drag_select_start: Option<XyPos>,
+    /// start of a right-click-drag formation line
+    formation_drag_start: Option<XyPos>,
 }
 
 impl Default for InputState {
@@ -39,8 +52,10 @@ impl Default for InputState {
             mousebtn: EventReader::default(),
             is_multi_select_on: false,
             is_toggle_select_on: false,
+            is_secondary_attack_on: false,
             last_mouse_action: None,
             drag_select_start: None,
+            formation_drag_start: None,
         }
     }
 }
@@ -48,6 +63,7 @@ impl Default for InputState {
 pub fn input_system(
     mut engine_commands: Commands,
     mut state: ResMut<InputState>,
+    local_player: Res<LocalPlayer>,
     cursor: Res<CursorState>,
     ev_keys: Res<Events<KeyboardInput>>,
     ev_mousebtn: Res<Events<MouseButtonInput>>,
@@ -61,6 +77,8 @@ pub fn input_system(
     )>,
 ) {
     let mut ui_commands: Vec<UnitUiCommand> = Vec::new();
+    // depends on each units current policy, so is sent separately
+    let mut cycle_targeting_policy = false;
 
     // Keyboard input
     for ev in state.keys.iter(&ev_keys) {
@@ -73,12 +91,30 @@ pub fn input_system(
                     KeyCode::R => ui_commands.push(UnitUiCommand::ToggleSpeed),
                     KeyCode::G => ui_commands.push(UnitUiCommand::ToggleGuardMode),
                     KeyCode::F => ui_commands.push(UnitUiCommand::ToggleFireAtWill),
+                    KeyCode::L => ui_commands.push(UnitUiCommand::ToggleSpacing),
+                    KeyCode::V => ui_commands.push(UnitUiCommand::ToggleFireMode),
+                    KeyCode::T => cycle_targeting_policy = true,
+                    KeyCode::Key1 => {
+                        ui_commands.push(UnitUiCommand::ToggleAbility(Ability::ShieldWall))
+                    }
+                    KeyCode::Key2 => {
+                        ui_commands.push(UnitUiCommand::ToggleAbility(Ability::Testudo))
+                    }
+                    KeyCode::Key3 => {
+                        ui_commands.push(UnitUiCommand::ToggleAbility(Ability::Phalanx))
+                    }
+                    KeyCode::Key4 => ui_commands.push(UnitUiCommand::ToggleAbility(Ability::Wedge)),
+                    KeyCode::Key5 => {
+                        ui_commands.push(UnitUiCommand::ToggleAbility(Ability::CantabrianCircle))
+                    }
+                    KeyCode::Key6 => ui_commands.push(UnitUiCommand::ToggleAbility(Ability::Brace)),
                     KeyCode::Tab => {
                         // remember, must be tuple here!
                         engine_commands.spawn((GameSpeedRequest::TogglePause,));
                     }
                     KeyCode::LShift => state.is_toggle_select_on = true,
                     KeyCode::LControl => state.is_multi_select_on = true,
+                    KeyCode::LAlt => state.is_secondary_attack_on = true,
                     _ => (),
                 };
             }
@@ -88,6 +124,7 @@ pub fn input_system(
                 match key {
                     KeyCode::LShift => state.is_toggle_select_on = false,
                     KeyCode::LControl => state.is_multi_select_on = false,
+                    KeyCode::LAlt => state.is_secondary_attack_on = false,
                     _ => (),
                 }
             }
@@ -112,7 +149,11 @@ pub fn input_system(
             } else {
                 state.drag_select_start = None;
             }
-        // TODO maybe have right click drag actions for movement paths?
+
+            if ev.button == MouseButton::Right {
+                // start formation drag action
+                state.formation_drag_start.replace(mouse_position.clone());
+            }
         } else {
             // process on release
 
@@ -129,7 +170,20 @@ pub fn input_system(
 
             // l/r command logic
             if ev.button == MouseButton::Right {
-                mouse_command.replace(MouseCommand::Action(mouse_position));
+                match state.formation_drag_start.take() {
+                    Some(start)
+                        if (mouse_position - start).length() > FORMATION_DRAG_MIN_LENGTH =>
+                    {
+                        log::debug!("formation drag");
+                        mouse_command.replace(MouseCommand::FormationDrag {
+                            start,
+                            end: mouse_position,
+                        });
+                    }
+                    _ => {
+                        mouse_command.replace(MouseCommand::Action(mouse_position));
+                    }
+                }
             } else if ev.button == MouseButton::Left {
                 if let Some(start) = state.drag_select_start {
                     log::debug!("drag select");
@@ -175,7 +229,7 @@ pub fn input_system(
                     selection_targets.push(entity);
                 }
             }
-            None => (),
+            Some(MouseCommand::FormationDrag { .. }) | None => (),
         }
     }
 
@@ -184,7 +238,15 @@ pub fn input_system(
         Some(MouseCommand::SingleSelect(_))
         | Some(MouseCommand::DragSelect { start: _, end: _ }) => {
             for (entity, mut unit, _transform, _sprite, _waypoint) in query.iter_mut() {
-                if selection_targets.contains(&entity) {
+                if selection_targets.contains(&entity) && unit.player_id != local_player.0 {
+                    // other players units can be looked at, but not selected
+                    log::info!(
+                        "player {}'s {:?}: {:?}",
+                        unit.player_id,
+                        unit.unit_type,
+                        unit.state
+                    );
+                } else if selection_targets.contains(&entity) {
                     if state.is_toggle_select_on {
                         unit.invert_select();
                     } else {
@@ -205,12 +267,42 @@ pub fn input_system(
                 UnitUiSpeedCommand::Walk
             };
             let cmd = if let Some(target) = selection_targets.into_iter().next() {
-                UnitUiCommand::Attack(target, speed)
+                if state.is_secondary_attack_on {
+                    UnitUiCommand::AttackSecondary(target)
+                } else {
+                    UnitUiCommand::Attack(target, speed)
+                }
             } else {
                 UnitUiCommand::Move(pos.clone(), speed)
             };
             ui_commands.push(cmd);
         }
+        Some(MouseCommand::FormationDrag { start, end }) => {
+            let speed = if is_double_click {
+                UnitUiSpeedCommand::Run
+            } else {
+                UnitUiSpeedCommand::Walk
+            };
+
+            // each selected unit takes its own slice of the line
+            let selected: Vec<Entity> = query
+                .iter_mut()
+                .filter(|(_, unit, _, _, _)| unit.is_selected())
+                .map(|(entity, _, _, _, _)| entity)
+                .collect();
+            let slices = split_formation_line(start.clone(), end.clone(), selected.len());
+
+            for (entity, (position, facing, frontage)) in selected.into_iter().zip(slices) {
+                let cmd = UnitUiCommand::Formation {
+                    position,
+                    facing,
+                    frontage,
+                    speed,
+                };
+                unit_events.send(UnitInteractionEvent::Ui(local_player.0, entity, cmd));
+                log::info!("Assigning {:?} command", cmd);
+            }
+        }
         None => (),
     }
 
@@ -219,7 +311,12 @@ pub fn input_system(
     for (entity, unit, _transform, _sprite, mut _waypoint) in query.iter_mut() {
         if unit.is_selected() {
             for cmd in ui_commands.clone() {
-                unit_events.send(UnitInteractionEvent::Ui(entity, cmd));
+                unit_events.send(UnitInteractionEvent::Ui(local_player.0, entity, cmd));
+                log::info!("Assigning {:?} command", cmd);
+            }
+            if cycle_targeting_policy {
+                let cmd = UnitUiCommand::SetTargetingPolicy(unit.targeting_policy.next());
+                unit_events.send(UnitInteractionEvent::Ui(local_player.0, entity, cmd));
                 log::info!("Assigning {:?} command", cmd);
             }
         }
@@ -0,0 +1,398 @@
+//! Working out when a battle is over, and who won.
+//!
+//! Each scenario has a list of victory conditions, and the battle ends as soon as any of them
+//! is met. Allied teams win or lose together. The result is sent as a `BattleOutcome` event and
+//! kept in the `BattleResource`, and the simulation is stopped for good (see `GameSpeed::stop`).
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::teams::*;
+use crate::*;
+
+/// points for each objective a team holds, for the time limit tiebreak
+const OBJECTIVE_POINTS: f32 = 100.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum VictoryCondition {
+    /// the last team with units left wins
+    Annihilation,
+    /// the last team with units that aren't routing wins
+    AllRouted,
+    /// after this many seconds, the team with the most points wins
+    TimeLimit(f32),
+    /// the side that holds every objective for this many seconds wins, allies included
+    HoldObjectives(f32),
+    /// ended by a scenario trigger or script, rather than checked each tick
+    Scripted,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PlayerStats {
+    pub units_alive: usize,
+    pub units_routing: usize,
+    pub units_lost: usize,
+    pub objectives_held: usize,
+    /// health of units still fighting, plus objectives held
+    pub points: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct BattleOutcome {
+    /// None for a draw. If allied teams win together, this is the one with the most points.
+    pub winner: Option<TeamId>,
+    /// the other teams that won alongside the winner
+    pub allies: Vec<TeamId>,
+    /// the condition that ended the battle
+    pub condition: VictoryCondition,
+    /// seconds of unpaused battle time
+    pub elapsed: f32,
+    pub stats: HashMap<PlayerId, PlayerStats>,
+}
+
+/// which team controls each objective on the map. None means nobody does.
+#[derive(Debug, Default)]
+pub struct ObjectiveControl(pub HashMap<Entity, Option<TeamId>>);
+
+#[derive(Debug, Default)]
+pub struct BattleResource {
+    pub conditions: Vec<VictoryCondition>,
+    pub elapsed: f32,
+    /// set once the battle is over
+    pub outcome: Option<BattleOutcome>,
+    /// every unit that has taken part in the battle, for counting losses
+    seen_units: HashMap<Entity, PlayerId>,
+    /// a team whose side holds every objective, and for how long
+    objective_holder: Option<(TeamId, f32)>,
+    /// set by `end_battle`, picked up by the victory system
+    scripted_result: Option<Option<TeamId>>,
+}
+
+impl PlayerStats {
+    fn add(&mut self, other: &PlayerStats) {
+        self.units_alive += other.units_alive;
+        self.units_routing += other.units_routing;
+        self.units_lost += other.units_lost;
+        self.objectives_held += other.objectives_held;
+        self.points += other.points;
+    }
+}
+
+impl BattleResource {
+    pub fn new(conditions: Vec<VictoryCondition>) -> Self {
+        BattleResource {
+            conditions,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.outcome.is_some()
+    }
+
+    /// a team whose side holds every objective, and for how many seconds
+    pub fn objective_holder(&self) -> Option<(TeamId, f32)> {
+        self.objective_holder
+    }
+
+    /// ends the battle on the next tick, with `winner` winning (or a draw for None)
+    pub fn end_battle(&mut self, winner: Option<TeamId>) {
+        self.scripted_result = Some(winner);
+    }
+
+    /// the condition that ends the battle and its result, if any. A scripted result takes
+    /// precedence over the scenarios own conditions.
+    pub fn decide(
+        &mut self,
+        team_stats: &HashMap<TeamId, PlayerStats>,
+        teams: &TeamsResource,
+    ) -> Option<(VictoryCondition, Option<TeamId>)> {
+        if let Some(winner) = self.scripted_result.take() {
+            return Some((VictoryCondition::Scripted, winner));
+        }
+
+        let battle = &*self;
+        battle.conditions.iter().find_map(|condition| {
+            condition
+                .check(battle, team_stats, teams)
+                .map(|w| (*condition, w))
+        })
+    }
+
+    /// tracks how long a single side has held every objective. Points can change hands
+    /// between allies without resetting the clock.
+    fn update_objectives(&mut self, objectives: &ObjectiveControl, teams: &TeamsResource, dt: f32) {
+        let owners: Option<Vec<TeamId>> = objectives.0.values().cloned().collect();
+        let holder = match owners {
+            Some(owners) if teams.sides(&owners).len() == 1 => owners.iter().min().cloned(),
+            _ => None,
+        };
+        self.objective_holder = match (holder, self.objective_holder) {
+            (Some(team), Some((held_by, time))) if teams.teams_allied(team, held_by) => {
+                Some((held_by, time + dt))
+            }
+            (Some(team), _) => Some((team, 0.0)),
+            (None, _) => None,
+        };
+    }
+}
+
+/// helper function
+/// splits the teams taking part into sides of teams that are all allied with each other
+fn sides(team_stats: &HashMap<TeamId, PlayerStats>, teams: &TeamsResource) -> Vec<Vec<TeamId>> {
+    let ids: Vec<TeamId> = team_stats.keys().cloned().collect();
+    teams.sides(&ids)
+}
+
+impl VictoryCondition {
+    /// returns the result if this condition ends the battle: the winning team, or None for a draw
+    pub fn check(
+        &self,
+        battle: &BattleResource,
+        team_stats: &HashMap<TeamId, PlayerStats>,
+        teams: &TeamsResource,
+    ) -> Option<Option<TeamId>> {
+        let points = |team: &TeamId| team_stats.get(team).map(|s| s.points).unwrap_or(0.0);
+        let by_points = |a: &f32, b: &f32| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal);
+        // the team with the most points speaks for its side
+        let leader = |side: &[TeamId]| {
+            side.iter()
+                .max_by(|a, b| by_points(&points(*a), &points(*b)))
+                .cloned()
+        };
+
+        let side_stats: Vec<(Vec<TeamId>, PlayerStats)> = sides(team_stats, teams)
+            .into_iter()
+            .map(|side| {
+                let mut total = PlayerStats::default();
+                for team in side.iter() {
+                    total.add(&team_stats[team]);
+                }
+                (side, total)
+            })
+            .collect();
+
+        let last_side_standing = |in_the_fight: &dyn Fn(&PlayerStats) -> bool| {
+            let remaining: Vec<&Vec<TeamId>> = side_stats
+                .iter()
+                .filter(|(_, stats)| in_the_fight(stats))
+                .map(|(side, _)| side)
+                .collect();
+            match remaining.as_slice() {
+                [] => Some(None),
+                [side] => Some(leader(side.as_slice())),
+                _ => None,
+            }
+        };
+
+        match self {
+            VictoryCondition::Annihilation => last_side_standing(&|s| s.units_alive > 0),
+            VictoryCondition::AllRouted => last_side_standing(&|s| s.units_alive > s.units_routing),
+            VictoryCondition::TimeLimit(limit) if battle.elapsed >= *limit => {
+                let mut ranked: Vec<&(Vec<TeamId>, PlayerStats)> = side_stats.iter().collect();
+                ranked.sort_by(|a, b| by_points(&b.1.points, &a.1.points));
+                match ranked.as_slice() {
+                    [(side, _)] => Some(leader(side.as_slice())),
+                    [(first, a), (_, b), ..] if a.points > b.points => {
+                        Some(leader(first.as_slice()))
+                    }
+                    _ => Some(None),
+                }
+            }
+            VictoryCondition::HoldObjectives(hold_time) => match battle.objective_holder {
+                Some((team, time)) if time >= *hold_time => Some(Some(team)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// tallies up each players units, and ends the battle when a victory condition is met
+pub fn victory_system(
+    time: Res<Time>,
+    mut game_speed: ResMut<GameSpeed>,
+    teams: Res<TeamsResource>,
+    objectives: Res<ObjectiveControl>,
+    mut battle: ResMut<BattleResource>,
+    mut outcomes: ResMut<Events<BattleOutcome>>,
+    units: Query<(
+        Entity,
+        &UnitComponent,
+        &HealthComponent,
+        Option<&MoraleComponent>,
+    )>,
+) {
+    if game_speed.is_paused() || battle.is_over() {
+        return;
+    }
+
+    battle.elapsed += time.delta_seconds();
+    battle.update_objectives(&objectives, &teams, time.delta_seconds());
+
+    let mut stats: HashMap<PlayerId, PlayerStats> = HashMap::new();
+    let mut alive = vec![];
+    for (entity, unit, health, morale) in units.iter() {
+        battle.seen_units.insert(entity, unit.player_id);
+        alive.push(entity);
+
+        let player = stats.entry(unit.player_id).or_default();
+        player.units_alive += 1;
+        if morale.map(|m| m.is_routing()).unwrap_or(false) {
+            player.units_routing += 1;
+        } else {
+            player.points += health.ratio() * MAX_HP;
+        }
+    }
+    for (entity, player) in battle.seen_units.iter() {
+        let player = stats.entry(*player).or_default();
+        if !alive.contains(entity) {
+            player.units_lost += 1;
+        }
+    }
+
+    let mut team_stats: HashMap<TeamId, PlayerStats> = HashMap::new();
+    for (player, player_stats) in stats.iter() {
+        if let Ok(team) = teams.team_of(*player) {
+            team_stats.entry(team).or_default().add(player_stats);
+        }
+    }
+    for holder in objectives.0.values() {
+        if let Some(team) = holder {
+            let team_stats = team_stats.entry(*team).or_default();
+            team_stats.objectives_held += 1;
+            team_stats.points += OBJECTIVE_POINTS;
+        }
+    }
+
+    // nothing to decide unless at least two sides have taken part
+    if sides(&team_stats, &teams).len() < 2 && battle.scripted_result.is_none() {
+        return;
+    }
+
+    if let Some((condition, winner)) = battle.decide(&team_stats, &teams) {
+        let mut allies: Vec<TeamId> = match winner {
+            Some(winner) => team_stats
+                .keys()
+                .filter(|t| **t != winner && teams.teams_allied(**t, winner))
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+        allies.sort();
+
+        let outcome = BattleOutcome {
+            winner,
+            allies,
+            condition,
+            elapsed: battle.elapsed,
+            stats,
+        };
+        log::info!("battle over: {:?}", outcome);
+        outcomes.send(outcome.clone());
+        battle.outcome = Some(outcome);
+        game_speed.stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_victory_conditions() {
+        let stats = |alive: usize, routing: usize, points: f32| PlayerStats {
+            units_alive: alive,
+            units_routing: routing,
+            points,
+            ..Default::default()
+        };
+        let teams = TeamsResource::from_setup(&TeamSetup {
+            teams: vec![vec![1], vec![2], vec![3]],
+            alliances: vec![(1, 2)],
+        });
+        let battle = BattleResource::new(vec![]);
+
+        let mut team_stats = HashMap::new();
+        team_stats.insert(0, stats(2, 2, 0.0));
+        team_stats.insert(1, stats(1, 0, 50.0));
+        assert_eq!(
+            VictoryCondition::Annihilation.check(&battle, &team_stats, &teams),
+            None
+        );
+        assert_eq!(
+            VictoryCondition::AllRouted.check(&battle, &team_stats, &teams),
+            Some(Some(1))
+        );
+        assert_eq!(
+            VictoryCondition::TimeLimit(10.0).check(&battle, &team_stats, &teams),
+            None
+        );
+
+        let mut battle = BattleResource::new(vec![]);
+        battle.elapsed = 10.0;
+        team_stats.insert(0, stats(2, 0, 50.0));
+        assert_eq!(
+            VictoryCondition::TimeLimit(10.0).check(&battle, &team_stats, &teams),
+            Some(None)
+        );
+
+        // allies win together, even though neither of them is the last team standing
+        let mut team_stats = HashMap::new();
+        team_stats.insert(0, stats(0, 0, 0.0));
+        team_stats.insert(1, stats(1, 0, 20.0));
+        team_stats.insert(2, stats(2, 0, 80.0));
+        assert_eq!(
+            VictoryCondition::Annihilation.check(&battle, &team_stats, &teams),
+            Some(Some(2))
+        );
+
+        // a scripted result takes precedence over the conditions, and is only used once
+        let mut battle = BattleResource::new(vec![VictoryCondition::Annihilation]);
+        battle.end_battle(None);
+        assert_eq!(
+            battle.decide(&team_stats, &teams),
+            Some((VictoryCondition::Scripted, None))
+        );
+        assert_eq!(
+            battle.decide(&team_stats, &teams),
+            Some((VictoryCondition::Annihilation, Some(2)))
+        );
+    }
+
+    #[test]
+    fn test_allies_hold_objectives_together() {
+        let teams = TeamsResource::from_setup(&TeamSetup {
+            teams: vec![vec![1], vec![2], vec![3]],
+            alliances: vec![(0, 1)],
+        });
+        let (a, b) = (Entity::new(1), Entity::new(2));
+        let mut objectives = ObjectiveControl::default();
+        let mut battle = BattleResource::new(vec![]);
+
+        objectives.0.insert(a, Some(0));
+        objectives.0.insert(b, Some(1));
+        battle.update_objectives(&objectives, &teams, 5.0);
+        battle.update_objectives(&objectives, &teams, 5.0);
+        assert_eq!(battle.objective_holder, Some((0, 5.0)));
+
+        // handing a point to an ally keeps the clock running
+        objectives.0.insert(a, Some(1));
+        battle.update_objectives(&objectives, &teams, 5.0);
+        assert_eq!(battle.objective_holder, Some((0, 10.0)));
+        assert_eq!(
+            VictoryCondition::HoldObjectives(10.0).check(&battle, &HashMap::new(), &teams),
+            Some(Some(0))
+        );
+
+        // but losing one to an enemy, or nobody holding it, doesn't
+        objectives.0.insert(b, Some(2));
+        battle.update_objectives(&objectives, &teams, 5.0);
+        assert_eq!(battle.objective_holder, None);
+        objectives.0.insert(b, None);
+        battle.update_objectives(&objectives, &teams, 5.0);
+        assert_eq!(battle.objective_holder, None);
+    }
+}